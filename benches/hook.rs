@@ -0,0 +1,97 @@
+//! The work the hook does on every prompt, for `.shadowenv.d`s of a few sizes. Run with
+//! `cargo bench --features bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use shadowenv::bench;
+use shadowenv::hash::Source;
+use shadowenv::lang::ShadowLang;
+use shadowenv::loader;
+use shadowenv::shadowenv::Shadowenv;
+use shadowenv::undo;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tempfile::TempDir;
+
+const SIZES: &[usize] = &[1, 10, 50];
+
+struct Fixture {
+    _dir: TempDir,
+    shadowenv_d: PathBuf,
+    source: Source,
+}
+
+fn fixture(files: usize) -> Fixture {
+    let dir = tempfile::tempdir().unwrap();
+    let shadowenv_d = bench::write_shadowenv_d(dir.path(), files).unwrap();
+    let source = loader::load(shadowenv_d.clone()).unwrap().unwrap();
+    Fixture {
+        _dir: dir,
+        shadowenv_d,
+        source,
+    }
+}
+
+fn base_env() -> HashMap<String, String> {
+    vec![
+        ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+        ("MANPATH", "/usr/share/man"),
+        ("HOME", "/home/me"),
+        ("SHELL", "/bin/zsh"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+fn activate(source: &Source) -> Shadowenv {
+    let hash = source.hash().unwrap();
+    let shadowenv = Shadowenv::new(base_env(), undo::Data::new(), hash);
+    ShadowLang::run_program(shadowenv, source.clone()).unwrap()
+}
+
+fn load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("loader::load");
+    for &files in SIZES {
+        let fixture = fixture(files);
+        group.bench_with_input(BenchmarkId::from_parameter(files), &fixture, |b, f| {
+            b.iter(|| loader::load(f.shadowenv_d.clone()).unwrap().unwrap().hash())
+        });
+    }
+    group.finish();
+}
+
+fn run_program(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ShadowLang::run_program");
+    for &files in SIZES {
+        let fixture = fixture(files);
+        group.bench_with_input(BenchmarkId::from_parameter(files), &fixture, |b, f| {
+            b.iter(|| activate(&f.source))
+        });
+    }
+    group.finish();
+}
+
+fn data(c: &mut Criterion) {
+    let mut format = c.benchmark_group("format_shadowenv_data");
+    for &files in SIZES {
+        let shadowenv = activate(&fixture(files).source);
+        format.bench_with_input(BenchmarkId::from_parameter(files), &shadowenv, |b, s| {
+            b.iter(|| bench::shadowenv_data(s).unwrap())
+        });
+    }
+    format.finish();
+
+    let mut parse = c.benchmark_group("undo::Data::from_str");
+    for &files in SIZES {
+        let data = bench::shadowenv_data(&activate(&fixture(files).source)).unwrap();
+        // past the hash and its colon.
+        let encoded = data[17..].to_string();
+        parse.bench_with_input(BenchmarkId::from_parameter(files), &encoded, |b, e| {
+            b.iter(|| e.parse::<undo::Data>().unwrap())
+        });
+    }
+    parse.finish();
+}
+
+criterion_group!(benches, load, run_program, data);
+criterion_main!(benches);
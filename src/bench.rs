@@ -0,0 +1,46 @@
+//! Inputs for the benchmarks in `benches/`, and the internals they measure. These are available to
+//! other crates with the `bench` feature.
+
+use crate::shadowenv::Shadowenv;
+
+use failure::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Write a `.shadowenv.d` of `files` programs into `dir`, returning its path. Each sets a few
+/// variables and pathlist entries, as a typical project's would.
+pub fn write_shadowenv_d(dir: &Path, files: usize) -> io::Result<PathBuf> {
+    let shadowenv_d = dir.join(".shadowenv.d");
+    fs::create_dir_all(&shadowenv_d)?;
+    for index in 0..files {
+        fs::write(
+            shadowenv_d.join(format!("{:03}_tool{}.lisp", index * 10, index)),
+            program(index),
+        )?;
+    }
+    Ok(shadowenv_d)
+}
+
+/// The program in the `index`th file written by `write_shadowenv_d`.
+pub fn program(index: usize) -> String {
+    format!(
+        r#";; tool{index}
+(provide "tool{index}" "1.{index}.0")
+(let ((root (path-concat "/opt/tool{index}" "1.{index}.0")))
+  (do
+    (env/set "TOOL{index}_ROOT" root)
+    (env/prepend-to-pathlist "PATH" (path-concat root "bin"))
+    (env/prepend-to-pathlist "MANPATH" (path-concat root "share/man"))))
+(when-let ((home (env/get "HOME")))
+  (env/set "TOOL{index}_CACHE" (path-concat home ".cache/tool{index}")))
+(env/remove-from-pathlist "PATH" "/opt/legacy{index}/bin")
+"#,
+        index = index
+    )
+}
+
+/// $__shadowenv_data as the hook would export it for `shadowenv`.
+pub fn shadowenv_data(shadowenv: &Shadowenv) -> Result<String, Error> {
+    shadowenv.format_shadowenv_data()
+}
@@ -1,5 +1,15 @@
 use clap::{App, AppSettings, Arg, ArgGroup, SubCommand};
 
+/// The output formats `hook` and `unload` have flags for, as reported by `capabilities`.
+pub const OUTPUT_FORMATS: &[&str] = &[
+    "porcelain",
+    "posix",
+    "fish",
+    "powershell",
+    "json",
+    "pretty-json",
+];
+
 pub fn app() -> App<'static, 'static> {
     let version = Box::leak(
         format!(
@@ -14,7 +24,7 @@ pub fn app() -> App<'static, 'static> {
     App::new("shadowenv")
         .version(&version[..])
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .subcommand(
+        .subcommand(with_output_formats(
             SubCommand::with_name("hook")
                 .about("Runs the shell hook. You shouldn't need to run this manually.")
                 .setting(AppSettings::DisableHelpSubcommand)
@@ -24,19 +34,31 @@ pub fn app() -> App<'static, 'static> {
                     Arg::with_name("$__shadowenv_data").required(false)
                 )
                 .arg(
-                    Arg::with_name("fish")
-                        .long("fish")
-                        .help("Format variable assignments for fish shell"),
+                    Arg::with_name("force")
+                        .long("force")
+                        .help("Force the shadowenv to be applied, even if the working directory has not changed."),
                 )
                 .arg(
-                    Arg::with_name("posix")
-                        .long("posix")
-                        .help("Format variable assignments for posix shells (default)"),
+                    Arg::with_name("login")
+                        .long("login")
+                        .help("The calling shell is a login shell (see is-login-shell)"),
                 )
                 .arg(
-                    Arg::with_name("force")
-                        .long("force")
-                        .help("Force the shadowenv to be applied, even if the working directory has not changed."),
+                    // The hook can't see a variable that isn't exported, so it has to be passed.
+                    Arg::with_name("no-export-data")
+                        .long("no-export-data")
+                        .requires("$__shadowenv_data")
+                        .help("Set $__shadowenv_data without exporting it, so that subshells don't inherit it (posix and fish only; pass \"$__shadowenv_data\" as an argument)"),
+                )
+                .arg(
+                    Arg::with_name("exit-code-on-no-change")
+                        .long("exit-code-on-no-change")
+                        .help("Exit with status 75, and print nothing, when there are no changes to apply."),
+                )
+                .arg(
+                    Arg::with_name("delta")
+                        .long("delta")
+                        .help("Only print variables changed since the previous activation, for callers which kept its env"),
                 )
                 .arg(
                     Arg::with_name("silent")
@@ -50,23 +72,28 @@ pub fn app() -> App<'static, 'static> {
                         .long("shellpid")
                         .takes_value(true)
                         .help("rather than looking up the PPID, use this as the shell's pid"),
-                )
-                .arg(
-                    Arg::with_name("porcelain")
-                        .long("porcelain")
-                        .help("Format variable assignments for machine parsing"),
-                )
-                .arg(
-                    Arg::with_name("json")
-                        .long("json")
-                        .help("Format variable assignments as JSON"),
-                )
+                ),
+        ))
+        .subcommand(with_output_formats(
+            SubCommand::with_name("unload")
+                .about("Prints the shell operations undoing the active shadowenv, whatever the current directory.")
+                .setting(AppSettings::DisableHelpSubcommand),
+        ))
+        .subcommand(
+            SubCommand::with_name("hook-multi")
+                .about("Prints, as JSON, the environment changes for each of several directories.")
+                .setting(AppSettings::DisableHelpSubcommand)
                 .arg(
                     Arg::with_name("pretty-json")
                         .long("pretty-json")
-                        .help("Format variable assignments as pretty JSON"),
+                        .help("Format the output as pretty JSON"),
                 )
-                .group(ArgGroup::with_name("format").args(&["porcelain", "posix", "fish", "json", "pretty-json"])),
+                .arg(
+                    Arg::with_name("dirs")
+                        .multiple(true)
+                        .required(true)
+                        .help("Directories to load the environment for"),
+                ),
         )
         .subcommand(
             SubCommand::with_name("diff")
@@ -92,7 +119,7 @@ pub fn app() -> App<'static, 'static> {
         )
         .subcommand(
             SubCommand::with_name("trust")
-                .about("Mark this directory as 'trusted', allowing shadowenv programs to be run.")
+                .about("Mark this directory as 'trusted', allowing its shadowenv programs, as they are now, to be run.")
                 .setting(AppSettings::DisableHelpSubcommand)
         )
         .subcommand(
@@ -130,6 +157,11 @@ pub fn app() -> App<'static, 'static> {
                              .required(true),
                 )
         )
+        .subcommand(
+            SubCommand::with_name("capabilities")
+                .about("Prints, as JSON, the output formats and builtins this version of shadowenv supports.")
+                .setting(AppSettings::DisableHelpSubcommand),
+        )
         .subcommand(
             SubCommand::with_name("init")
                 .about("Prints a script which can be eval'd to set up shadowenv in various shells.")
@@ -149,3 +181,61 @@ pub fn app() -> App<'static, 'static> {
                 )
         )
 }
+
+/// The flags choosing which format the shell operations are printed in, for subcommands printing
+/// them.
+fn with_output_formats(subcommand: App<'static, 'static>) -> App<'static, 'static> {
+    subcommand
+        .arg(
+            Arg::with_name("fish")
+                .long("fish")
+                .help("Format variable assignments for fish shell"),
+        )
+        .arg(
+            Arg::with_name("posix")
+                .long("posix")
+                .help("Format variable assignments for posix shells (default)"),
+        )
+        .arg(
+            Arg::with_name("porcelain")
+                .long("porcelain")
+                .help("Format variable assignments for machine parsing"),
+        )
+        .arg(
+            Arg::with_name("end-marker")
+                .long("end-marker")
+                .requires("porcelain")
+                .help("End porcelain output with an end-of-stream record (opcode 0)"),
+        )
+        .arg(
+            Arg::with_name("powershell")
+                .long("powershell")
+                .help("Format variable assignments for PowerShell"),
+        )
+        .arg(
+            Arg::with_name("path-separator")
+                .long("path-separator")
+                .takes_value(true)
+                .possible_values(&[":", ";"])
+                .requires("powershell")
+                .help("The PATH separator PowerShell expects (default: ';' on Windows, ':' elsewhere)"),
+        )
+        .arg(
+            Arg::with_name("shell")
+                .long("shell")
+                .takes_value(true)
+                .value_name("SHELL")
+                .help("Format variable assignments for SHELL, or with 'auto' for the shell running shadowenv"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("Format variable assignments as JSON"),
+        )
+        .arg(
+            Arg::with_name("pretty-json")
+                .long("pretty-json")
+                .help("Format variable assignments as pretty JSON"),
+        )
+        .group(ArgGroup::with_name("format").args(OUTPUT_FORMATS).arg("shell"))
+}
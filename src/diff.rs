@@ -1,7 +1,9 @@
+use crate::shadowenv::env_from_os;
 use crate::undo;
 
 use std::collections::BTreeMap;
 use std::env;
+use std::str::FromStr;
 
 trait Logger {
     fn print(&mut self, value: String);
@@ -18,7 +20,7 @@ impl Logger for StdoutLogger {
 pub fn run(verbose: bool, color: bool, shadowenv_data: String) -> i32 {
     run_with_logger(
         &mut StdoutLogger {},
-        env::vars().collect(),
+        env_from_os(env::vars_os()).into_iter().collect(),
         verbose,
         color,
         shadowenv_data,
@@ -5,7 +5,16 @@ use std::vec::Vec;
 
 /// Execute the provided command (argv) after loading the environment from the current directory
 pub fn run(pathbuf: PathBuf, shadowenv_data: String, argv: Vec<&str>) -> Result<(), Error> {
-    if let Some((shadowenv, _)) = hook::load_env(pathbuf, shadowenv_data, true)? {
+    if let Some((shadowenv, _)) = hook::load_env(
+        pathbuf,
+        shadowenv_data,
+        hook::LoadOptions {
+            force: true,
+            login: false,
+            exec: true,
+            shellpid: None,
+        },
+    )? {
         hook::mutate_own_env(&shadowenv)?;
     }
 
@@ -1,3 +1,4 @@
+use std::cmp::Ordering;
 use std::fmt;
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone)]
@@ -10,6 +11,38 @@ impl Feature {
     pub fn new(name: String, version: Option<String>) -> Self {
         Feature { name, version }
     }
+
+    /// Whether this is the feature `name`, at `min_version` or newer if one is given. A feature
+    /// provided without a version never satisfies a minimum version.
+    pub fn satisfies(&self, name: &str, min_version: Option<&str>) -> bool {
+        if self.name != name {
+            return false;
+        }
+        match (min_version, &self.version) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(min), Some(version)) => compare_versions(version, min) != Ordering::Less,
+        }
+    }
+}
+
+/// Compare versions component-by-component on `.`, numerically where both components are numbers
+/// and lexically otherwise. Missing trailing components count as 0, so "11" == "11.0".
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let a: Vec<&str> = a.split('.').collect();
+    let b: Vec<&str> = b.split('.').collect();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or("0");
+        let y = b.get(i).copied().unwrap_or("0");
+        let ord = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
 }
 
 impl fmt::Display for Feature {
@@ -20,3 +53,31 @@ impl fmt::Display for Feature {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare_versions() {
+        assert_eq!(compare_versions("11", "11.0"), Ordering::Equal);
+        assert_eq!(compare_versions("11.0.2", "11"), Ordering::Greater);
+        assert_eq!(compare_versions("1.8", "11"), Ordering::Less);
+        assert_eq!(compare_versions("3.10.0", "3.9.9"), Ordering::Greater);
+        assert_eq!(compare_versions("beta", "alpha"), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_satisfies() {
+        let java = Feature::new("java".to_string(), Some("17.0.1".to_string()));
+        assert!(java.satisfies("java", None));
+        assert!(java.satisfies("java", Some("11")));
+        assert!(java.satisfies("java", Some("17.0.1")));
+        assert!(!java.satisfies("java", Some("21")));
+        assert!(!java.satisfies("ruby", None));
+
+        let unversioned = Feature::new("java".to_string(), None);
+        assert!(unversioned.satisfies("java", None));
+        assert!(!unversioned.satisfies("java", Some("11")));
+    }
+}
@@ -3,7 +3,9 @@ use blake2::VarBlake2b;
 use failure::{Error, Fail};
 use std::cmp::Ord;
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::convert::TryInto;
+use std::fmt;
 use std::result::Result;
 use std::str::FromStr;
 use std::u64;
@@ -12,12 +14,25 @@ const FILE_SEPARATOR: &str = "\x1C";
 const GROUP_SEPARATOR: &str = "\x1D";
 
 #[derive(Debug, Clone)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde_derive::Serialize, serde_derive::Deserialize)
+)]
 pub struct Source {
     pub dir: String,
     pub files: Vec<SourceFile>,
+    /// other `.shadowenv.d` directories pulled in by `include`, keyed by their canonical path.
+    pub includes: BTreeMap<String, Source>,
+    /// project files read by `read-json` and `read-toml`, keyed by the path they're read by.
+    #[cfg_attr(any(test, feature = "serde"), serde(default))]
+    pub data_files: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Clone, Eq)]
+#[cfg_attr(
+    any(test, feature = "serde"),
+    derive(serde_derive::Serialize, serde_derive::Deserialize)
+)]
 pub struct SourceFile {
     pub name: String,
     pub contents: String,
@@ -41,6 +56,8 @@ impl PartialEq for SourceFile {
     }
 }
 
+/// The identity of a Source. Its canonical form, as used in $__shadowenv_data, is 16 hex digits:
+/// that's what `Display` prints, `FromStr` parses, and serde (with the `serde` feature) uses.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Hash {
     pub hash: u64,
@@ -52,24 +69,36 @@ struct WrongInputSize;
 
 impl Source {
     pub fn new(dir: String) -> Self {
-        Source { dir, files: vec![] }
+        Source {
+            dir,
+            files: vec![],
+            includes: BTreeMap::new(),
+            data_files: BTreeMap::new(),
+        }
     }
 
     pub fn add_file(&mut self, name: String, contents: String) {
         self.files.push(SourceFile { name, contents })
     }
 
+    /// The number of files that will be evaluated, counting those of included directories.
+    pub fn file_count(&self) -> usize {
+        self.files.len() + self.includes.values().map(|s| s.files.len()).sum::<usize>()
+    }
+
     pub fn hash(&self) -> Result<u64, Error> {
         if self.files.is_empty() {
             return Ok(0);
         }
         let mut hasher = VarBlake2b::new(8)?;
-        hasher.input(&self.dir);
-        hasher.input(FILE_SEPARATOR);
-        for file in self.files.iter() {
-            hasher.input(&file.name);
+        self.input_files(&mut hasher);
+        for include in self.includes.values() {
+            include.input_files(&mut hasher);
+        }
+        for (path, contents) in self.data_files.iter() {
+            hasher.input(path);
             hasher.input(GROUP_SEPARATOR);
-            hasher.input(&file.contents);
+            hasher.input(contents);
             hasher.input(FILE_SEPARATOR);
         }
         let mut sum: u64 = 0;
@@ -78,6 +107,17 @@ impl Source {
         });
         Ok(sum)
     }
+
+    fn input_files(&self, hasher: &mut VarBlake2b) {
+        hasher.input(&self.dir);
+        hasher.input(FILE_SEPARATOR);
+        for file in self.files.iter() {
+            hasher.input(&file.name);
+            hasher.input(GROUP_SEPARATOR);
+            hasher.input(&file.contents);
+            hasher.input(FILE_SEPARATOR);
+        }
+    }
 }
 
 impl FromStr for Hash {
@@ -92,9 +132,24 @@ impl FromStr for Hash {
     }
 }
 
-impl ToString for Hash {
-    fn to_string(&self) -> String {
-        format!("{:016x}", self.hash)
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.hash)
+    }
+}
+
+#[cfg(any(test, feature = "serde"))]
+impl serde::Serialize for Hash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(any(test, feature = "serde"))]
+impl<'de> serde::Deserialize<'de> for Hash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let key = <std::borrow::Cow<str> as serde::Deserialize>::deserialize(deserializer)?;
+        Hash::from_str(&key).map_err(serde::de::Error::custom)
     }
 }
 
@@ -118,6 +173,8 @@ mod tests {
             Source {
                 dir: Arbitrary::arbitrary(g),
                 files: Arbitrary::arbitrary(g),
+                includes: BTreeMap::new(),
+                data_files: BTreeMap::new(),
             }
         }
     }
@@ -144,6 +201,87 @@ mod tests {
         hash.hash == Hash::from_str(&hash.to_string()).unwrap().hash
     }
 
+    #[quickcheck]
+    fn hash_serde_roundtrip(hash: Hash) -> bool {
+        let json = serde_json::to_string(&hash).unwrap();
+        json == format!("\"{}\"", hash) && serde_json::from_str::<Hash>(&json).unwrap() == hash
+    }
+
+    #[test]
+    fn test_hash_deserialize_rejects_bad_input() {
+        assert!(serde_json::from_str::<Hash>("\"1234\"").is_err());
+        assert!(serde_json::from_str::<Hash>("\"zzzzzzzzzzzzzzzz\"").is_err());
+        assert!(serde_json::from_str::<Hash>("1234").is_err());
+    }
+
+    #[test]
+    fn test_source_serde_roundtrip() {
+        let mut shared = Source::new("/shared".to_string());
+        shared.add_file("b.lisp".to_string(), "(env/set \"A\" \"1\")".to_string());
+        let mut source = Source::new("/project".to_string());
+        source.add_file("a.lisp".to_string(), "(include \"/shared\")".to_string());
+        source.includes.insert("/shared".to_string(), shared);
+
+        let json = serde_json::to_string(&source).unwrap();
+        let decoded: Source = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.dir, source.dir);
+        assert_eq!(decoded.files, source.files);
+        assert_eq!(
+            decoded.includes["/shared"].files,
+            source.includes["/shared"].files
+        );
+        assert_eq!(decoded.hash().unwrap(), source.hash().unwrap());
+    }
+
+    #[test]
+    fn test_includes_contribute_to_hash() {
+        let mut source = Source::new("/project".to_string());
+        source.add_file("a.lisp".to_string(), "(include \"/shared\")".to_string());
+        let without_include = source.hash().unwrap();
+
+        let mut shared = Source::new("/".to_string());
+        shared.add_file("b.lisp".to_string(), "(env/set \"A\" \"1\")".to_string());
+        let mut with_include = source.clone();
+        with_include
+            .includes
+            .insert("/shared".to_string(), shared.clone());
+        assert_ne!(without_include, with_include.hash().unwrap());
+
+        shared.files[0].contents = "(env/set \"A\" \"2\")".to_string();
+        let mut with_changed_include = source;
+        with_changed_include
+            .includes
+            .insert("/shared".to_string(), shared);
+        assert_ne!(
+            with_include.hash().unwrap(),
+            with_changed_include.hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_data_files_contribute_to_hash() {
+        let mut source = Source::new("/project".to_string());
+        source.add_file(
+            "a.lisp".to_string(),
+            "(read-json \"package.json\" \"engines.node\")".to_string(),
+        );
+        let without_data = source.hash().unwrap();
+
+        let mut with_data = source.clone();
+        with_data.data_files.insert(
+            "package.json".to_string(),
+            r#"{"engines": {"node": "16"}}"#.to_string(),
+        );
+        assert_ne!(without_data, with_data.hash().unwrap());
+
+        let mut with_changed_data = source;
+        with_changed_data.data_files.insert(
+            "package.json".to_string(),
+            r#"{"engines": {"node": "18"}}"#.to_string(),
+        );
+        assert_ne!(with_data.hash().unwrap(), with_changed_data.hash().unwrap());
+    }
+
     #[quickcheck]
     fn source_hash_is_stable(source: Source) -> bool {
         let a = source.hash();
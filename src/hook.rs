@@ -1,105 +1,515 @@
 use crate::hash::{Hash, Source};
-use crate::lang;
 use crate::loader;
-use crate::output;
-use crate::shadowenv::Shadowenv;
+use crate::output::{self, Timings};
+use crate::shadowenv::{self, Export, Shadowenv};
 use crate::trust;
 use crate::undo;
 use serde_derive::Serialize;
 
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
 use std::env;
-use std::path::PathBuf;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
 use std::result::Result;
 use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
 use crate::lang::ShadowLang;
 use failure::Error;
 use shell_escape as shell;
 
+/// With `--exit-code-on-no-change`, the hook exits with this status when it has nothing to print,
+/// so that shell integrations can skip evaluating its output.
+pub const NO_CHANGE_EXIT_CODE: i32 = 75;
+
+#[derive(Clone, Copy)]
 pub enum VariableOutputMode {
     FishMode,
-    PorcelainMode,
-    PosixMode,
+    /// Records for machine parsing, ended by an end-of-stream record if `end_marker` is set.
+    PorcelainMode {
+        end_marker: bool,
+    },
+    /// Shells taking `export`s, quoting values with control characters in them as `$'...'` if
+    /// `ansi_c_quotes` is set, since not every POSIX shell reads that.
+    PosixMode {
+        ansi_c_quotes: bool,
+    },
+    /// PowerShell, which expects PATH entries separated by `path_separator`.
+    PowerShellMode {
+        path_separator: char,
+    },
     JsonMode,
     PrettyJsonMode,
 }
 
+/// The PATH separator PowerShell uses on this OS: Windows PowerShell uses `;`, while PowerShell
+/// Core (`pwsh`) on Linux or macOS uses `:` like every other shell there.
+pub fn default_powershell_path_separator() -> char {
+    match env::consts::OS {
+        "windows" => ';',
+        _ => ':',
+    }
+}
+
+/// The output mode for a shell, given its name or the path to it as in `$SHELL` or a process
+/// listing (where login shells show up as `-zsh`). Shells we don't know the output for are `None`.
+pub fn shell_output_mode(shell: &str) -> Option<VariableOutputMode> {
+    let name = Path::new(shell.trim().trim_start_matches('-'))
+        .file_name()?
+        .to_str()?;
+    match name.trim_end_matches(".exe") {
+        "fish" => Some(VariableOutputMode::FishMode),
+        "pwsh" | "powershell" => Some(VariableOutputMode::PowerShellMode {
+            path_separator: default_powershell_path_separator(),
+        }),
+        "bash" | "zsh" | "ksh" | "mksh" => Some(VariableOutputMode::PosixMode {
+            ansi_c_quotes: true,
+        }),
+        "sh" | "dash" | "ash" | "yash" => Some(VariableOutputMode::PosixMode {
+            ansi_c_quotes: false,
+        }),
+        _ => None,
+    }
+}
+
+/// Work out which shell wants our output, for `--shell=auto`: `$SHADOWENV_SHELL` if it's set (to
+/// correct the guess), then the shell process itself, then the user's `$SHELL`. If none of these
+/// names a shell we know, posix output is the best bet.
+pub fn detect_output_mode(shellpid: u32) -> VariableOutputMode {
+    env::var("SHADOWENV_SHELL")
+        .ok()
+        .filter(|s| !s.is_empty())
+        .or_else(|| process_name(shellpid))
+        .and_then(|shell| shell_output_mode(&shell))
+        .or_else(|| env::var("SHELL").ok().and_then(|s| shell_output_mode(&s)))
+        .unwrap_or(VariableOutputMode::PosixMode {
+            ansi_c_quotes: true,
+        })
+}
+
+fn process_name(pid: u32) -> Option<String> {
+    if let Ok(comm) = std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        return Some(comm.trim().to_string());
+    }
+    let output = std::process::Command::new("ps")
+        .args(["-o", "comm=", "-p", &pid.to_string()])
+        .output()
+        .ok()?;
+    let name = String::from_utf8(output.stdout).ok()?.trim().to_string();
+    Some(name).filter(|n| !n.is_empty())
+}
+
 #[derive(Serialize, Debug)]
 struct Modifications {
     schema: String,
     exported: HashMap<String, Option<String>>,
-    unexported: HashMap<String, Option<String>>, // Legacy. Not used, just shows up empty in json
+    /// variables set with `env/set-unexported`. Unsets always go in `exported`.
+    unexported: HashMap<String, Option<String>>,
 }
 
 impl Modifications {
-    fn new(exports: HashMap<String, Option<String>>) -> Modifications {
-        return Modifications {
+    fn new(exports: HashMap<String, Export>) -> Modifications {
+        let mut modifs = Modifications {
             schema: "v2".to_string(),
-            exported: exports,
+            exported: HashMap::new(),
             unexported: HashMap::new(),
         };
+        for (k, export) in exports {
+            if export.exported {
+                modifs.exported.insert(k, export.value);
+            } else {
+                modifs.unexported.insert(k, export.value);
+            }
+        }
+        modifs
     }
 }
 
+/// Load and apply the env for a directory.
 pub fn run(
     pathbuf: PathBuf,
     shadowenv_data: String,
     mode: VariableOutputMode,
     force: bool,
 ) -> Result<(), Error> {
-    match load_env(pathbuf, shadowenv_data, force)? {
-        Some((shadowenv, activation)) => {
-            apply_env(&shadowenv, mode, activation)?;
-            Ok(())
+    run_reporting_change(pathbuf, shadowenv_data, mode, force, HookOptions::default()).map(|_| ())
+}
+
+/// The hook's switches beyond those `run` takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookOptions {
+    /// Whether the calling shell is a login shell, for programs using `is-login-shell`.
+    pub login: bool,
+    /// Print only what changed since the previous activation, rather than all of its exports.
+    pub delta: bool,
+    /// Whether to export $__shadowenv_data, rather than leave it as a shell-local variable.
+    pub export_data: bool,
+    /// The pid of the calling shell, if it isn't our parent.
+    pub shellpid: Option<u32>,
+}
+
+impl Default for HookOptions {
+    fn default() -> Self {
+        HookOptions {
+            login: false,
+            delta: false,
+            export_data: true,
+            shellpid: None,
         }
-        None => Ok(()),
     }
 }
 
-pub fn load_env(
+/// Like `run`, with the rest of the hook's switches, returning whether there was anything to apply
+/// so that the binary can tell the shell when there's nothing to eval.
+pub fn run_reporting_change(
     pathbuf: PathBuf,
     shadowenv_data: String,
+    mode: VariableOutputMode,
     force: bool,
-) -> Result<Option<(Shadowenv, bool)>, Error> {
-    let mut parts = shadowenv_data.splitn(2, ":");
-    let prev_hash = parts.next();
-    let json_data = parts.next().unwrap_or("{}");
-
-    let active: Option<Hash> = match prev_hash {
-        None => None,
-        Some("") => None,
-        Some("0000000000000000") => None,
-        Some(x) => Some(Hash::from_str(x)?),
-    };
+    options: HookOptions,
+) -> Result<bool, Error> {
+    let end = mode;
+    let result = load_env(
+        pathbuf,
+        shadowenv_data,
+        LoadOptions {
+            force,
+            login: options.login,
+            exec: false,
+            shellpid: options.shellpid,
+        },
+    )
+    .and_then(|loaded| match loaded {
+        Some((mut shadowenv, activation)) => {
+            shadowenv.set_export_data(options.export_data);
+            apply_env(&shadowenv, mode, activation, options.delta)?;
+            remove_stale_cache_dirs(&shadowenv);
+            emit_event(&shadowenv, activation);
+            Ok(true)
+        }
+        None => Ok(false),
+    });
+    end_porcelain(&mut io::stdout().lock(), &end, &result)?;
+    result
+}
 
-    let target: Option<Source> = load_trusted_source(pathbuf)?;
+/// Undo the active shadowenv, if any, whatever the current directory: the shell ends up exactly
+/// as if it had left for a directory without a `.shadowenv.d`.
+pub fn unload(shadowenv_data: String, mode: VariableOutputMode) -> Result<(), Error> {
+    let vars: Vec<(OsString, OsString)> = env::vars_os().collect();
+    let non_utf8 = shadowenv::non_utf8_vars(vars.iter());
+    let env = shadowenv::env_from_os(vars.into_iter());
+    let end = mode;
+    let result = load_unloaded_env(shadowenv_data, env).and_then(|loaded| {
+        if let Some(mut shadowenv) = loaded {
+            shadowenv.leave_alone(non_utf8);
+            apply_env(&shadowenv, mode, false, false)?;
+            remove_stale_cache_dirs(&shadowenv);
+            emit_event(&shadowenv, false);
+        }
+        Ok(())
+    });
+    end_porcelain(&mut io::stdout().lock(), &end, &result)?;
+    result
+}
 
-    match (&active, &target) {
-        (None, None) => {
-            return Ok(None);
+/// Finish porcelain output, however the run went: with the record for the error, if it has one,
+/// and then the end-of-stream record, if it was asked for. Consumers reading from a persistent
+/// pipe can count on the end marker even when nothing changed, or the hook failed.
+fn end_porcelain<T>(
+    out: &mut dyn Write,
+    mode: &VariableOutputMode,
+    result: &Result<T, Error>,
+) -> io::Result<()> {
+    if let VariableOutputMode::PorcelainMode { end_marker } = mode {
+        if let Some(record) = result
+            .as_ref()
+            .err()
+            .and_then(output::format_hook_error_porcelain)
+        {
+            write!(out, "{}", record)?;
         }
-        (Some(a), Some(t)) if a.hash == t.hash()? && !force => {
-            return Ok(None);
+        if *end_marker {
+            write!(out, "\x00\x1F\x1F\x1E")?;
+        }
+    }
+    Ok(())
+}
+
+/// Announce the change to SHADOWENV_EVENT_FD, if it names a file descriptor the calling process
+/// keeps open for that, and there's a change to announce. Nothing's written otherwise, and failing
+/// to write isn't an error: the shell has its env either way.
+fn emit_event(shadowenv: &Shadowenv, activated: bool) {
+    let fd = env::var("SHADOWENV_EVENT_FD")
+        .ok()
+        .and_then(|v| event_fd(&v));
+    if let Some(fd) = fd {
+        if is_announced(shadowenv, activated).unwrap_or(false) {
+            let _ = write_event(fd, shadowenv, activated);
+        }
+    }
+}
+
+/// The descriptor SHADOWENV_EVENT_FD names, if it's one we can write events to: an open one, past
+/// stdin, stdout (which the shell evaluates) and stderr.
+fn event_fd(value: &str) -> Option<std::os::unix::io::RawFd> {
+    let fd = value.parse::<std::os::unix::io::RawFd>().ok()?;
+    if fd < 3 || unsafe { libc::fcntl(fd, libc::F_GETFD) } == -1 {
+        return None;
+    }
+    Some(fd)
+}
+
+fn write_event(
+    fd: std::os::unix::io::RawFd,
+    shadowenv: &Shadowenv,
+    activated: bool,
+) -> Result<(), Error> {
+    use std::os::unix::io::FromRawFd;
+    let event = output::Event::new(
+        activated,
+        shadowenv.target_hash().to_string(),
+        shadowenv.features(),
+    );
+    let line = serde_json::to_string(&event)? + "\n";
+    // the descriptor belongs to whoever asked for events, so it mustn't be closed here.
+    let mut file = std::mem::ManuallyDrop::new(unsafe { std::fs::File::from_raw_fd(fd) });
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// The hash of the activation `shadowenv_data` describes, if anything is active.
+fn active_hash(shadowenv_data: &str) -> Result<Option<Hash>, Error> {
+    match shadowenv_data.split(':').next() {
+        None | Some("") | Some("0000000000000000") => Ok(None),
+        Some(x) => Ok(Some(Hash::from_str(x)?)),
+    }
+}
+
+/// Undo the activation `shadowenv_data` describes, marking it as unloaded so that the hook
+/// doesn't activate it again straight away. Unloading when already unloaded keeps the marker.
+fn load_unloaded_env(
+    shadowenv_data: String,
+    env: HashMap<String, String>,
+) -> Result<Option<Shadowenv>, Error> {
+    let active = active_hash(&shadowenv_data)?;
+    match load_env_from_source(None, shadowenv_data, env, LoadOptions::default(), &mut None)? {
+        Some((mut shadowenv, _)) => {
+            let unloaded = match active {
+                Some(hash) => Some(hash),
+                None => shadowenv.previously_unloaded()?,
+            };
+            shadowenv.set_unloaded(unloaded);
+            Ok(Some(shadowenv))
         }
-        (_, _) => (),
+        None => Ok(None),
+    }
+}
+
+/// What `unload` prints, for shells driving deactivation themselves: the variables to set or
+/// unset in `env` to undo the shadowenv `shadowenv_data` describes, restoring the values it
+/// replaced. Pathlists only lose the entries it added, keeping any the user added since. With
+/// nothing active, there's nothing to do. `__shadowenv_data` isn't unset, but marks the
+/// activation as unloaded: the next hook run leaves that version of the project inactive, rather
+/// than activating it again, until the shell leaves it.
+pub fn deactivation_exports(
+    shadowenv_data: String,
+    env: HashMap<String, String>,
+) -> Result<HashMap<String, Export>, Error> {
+    match load_unloaded_env(shadowenv_data, env)? {
+        Some(shadowenv) => shadowenv.exports(),
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Load the environment for several directories at once, printing a JSON object mapping each
+/// directory (as given) to its modifications. Directories needing no changes map to `null`, and
+/// directories which failed to load map to the same error envelope the JSON hook prints.
+pub fn run_multi(dirs: Vec<PathBuf>, shadowenv_data: String, pretty: bool) -> Result<(), Error> {
+    let results = load_multi(dirs, &shadowenv_data)?;
+    if pretty {
+        println!("{}", serde_json::to_string_pretty(&results)?);
+    } else {
+        println!("{}", serde_json::to_string(&results)?);
+    }
+    Ok(())
+}
+
+fn load_multi(
+    dirs: Vec<PathBuf>,
+    shadowenv_data: &str,
+) -> Result<BTreeMap<String, serde_json::Value>, Error> {
+    let mut results = BTreeMap::new();
+    for dir in dirs {
+        let key = dir.to_string_lossy().to_string();
+        let loaded = std::fs::canonicalize(&dir)
+            .map_err(Error::from)
+            .and_then(|dir| {
+                load_env(
+                    dir,
+                    shadowenv_data.to_string(),
+                    LoadOptions {
+                        force: true,
+                        ..LoadOptions::default()
+                    },
+                )
+            })
+            .and_then(|loaded| loaded.map(|(shadowenv, _)| shadowenv.exports()).transpose());
+        let result = match loaded {
+            Ok(Some(exports)) => serde_json::to_value(Modifications::new(exports))?,
+            Ok(None) => serde_json::Value::Null,
+            Err(err) => output::hook_error_json(&err),
+        };
+        results.insert(key, result);
+    }
+    Ok(results)
+}
+
+/// Run `f`, recording how long it took in `slot` if we're timing at all.
+fn timed<T>(slot: Option<&mut Duration>, f: impl FnOnce() -> T) -> T {
+    match slot {
+        None => f(),
+        Some(slot) => {
+            let start = Instant::now();
+            let result = f();
+            *slot = start.elapsed();
+            result
+        }
+    }
+}
+
+/// How `load_env` decides what to activate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoadOptions {
+    /// Run the program even when the shell has this very activation applied already.
+    pub force: bool,
+    /// Whether the calling shell is a login shell, for programs using `is-login-shell`.
+    pub login: bool,
+    /// Whether it's for `shadowenv exec`, which needs the exec-only variables even when the shell
+    /// has this very activation applied already: they never reach the shell, so it can't have them.
+    pub exec: bool,
+    /// The pid of the calling shell, if it isn't our parent.
+    pub shellpid: Option<u32>,
+}
+
+/// Load the environment for `pathbuf`.
+pub fn load_env(
+    pathbuf: PathBuf,
+    shadowenv_data: String,
+    options: LoadOptions,
+) -> Result<Option<(Shadowenv, bool)>, Error> {
+    let mut timings = if output::timing_enabled() {
+        Some(Timings::default())
+    } else {
+        None
+    };
+
+    let target: Option<Source> = timed(timings.as_mut().map(|t| &mut t.load), || {
+        load_trusted_source(pathbuf)
+    })?;
+    if let (Some(timings), Some(target)) = (timings.as_mut(), &target) {
+        timings.source_dir = Some(target.dir.clone());
+        timings.files = target.file_count();
+    }
+
+    let vars: Vec<(OsString, OsString)> = env::vars_os().collect();
+    let non_utf8 = shadowenv::non_utf8_vars(vars.iter());
+    let result = load_env_from_source(
+        target,
+        shadowenv_data,
+        shadowenv::env_from_os(vars.into_iter()),
+        options,
+        &mut timings,
+    );
+    if let Some(timings) = &timings {
+        output::print_timings(timings);
     }
+    let mut result = result?;
+    if let Some((shadowenv, _)) = &mut result {
+        shadowenv.leave_alone(non_utf8);
+    }
+    Ok(result)
+}
+
+fn load_env_from_source(
+    target: Option<Source>,
+    shadowenv_data: String,
+    env: HashMap<String, String>,
+    options: LoadOptions,
+    timings: &mut Option<Timings>,
+) -> Result<Option<(Shadowenv, bool)>, Error> {
+    let json_data = shadowenv_data
+        .split_once(':')
+        .map_or("{}", |(_, json)| json);
+    let active = active_hash(&shadowenv_data)?;
 
     let target_hash = match &target {
-        Some(t) => t.hash().unwrap_or(0),
+        Some(t) => timed(timings.as_mut().map(|t| &mut t.hash), || t.hash())?,
         None => 0,
     };
 
+    if let (Some(a), Some(_)) = (&active, &target) {
+        if a.hash == target_hash && !options.force {
+            return Ok(None);
+        }
+    }
     let data = undo::Data::from_str(json_data)?;
-    let shadowenv = Shadowenv::new(env::vars().collect(), data, target_hash);
+    // After `unload`, the version of the project it undid stays inactive, even when forced, until
+    // the shell leaves it or it changes. Leaving is only a change to $__shadowenv_data, to drop
+    // the marker.
+    if active.is_none() {
+        let unloaded = match &data.unloaded {
+            Some(hash) => Some(Hash::from_str(hash)?),
+            None => None,
+        };
+        match (&unloaded, &target) {
+            (None, None) => return Ok(None),
+            (Some(u), Some(_)) if u.hash == target_hash => return Ok(None),
+            (_, _) => (),
+        }
+    }
+
+    // The hash covers the directory as well as the files, so a matching one means the very same
+    // `.shadowenv.d` is already active, as it is in a nested shell.
+    let inherited = matches!(&active, Some(a) if target.is_some() && a.hash == target_hash);
+
+    let mut shadowenv = Shadowenv::new(env, data, target_hash);
+    shadowenv.set_login_shell(options.login);
+    if let Some(pid) = options.shellpid {
+        shadowenv.set_shell_pid(pid);
+    }
+    // Re-activating overwrites whatever the user exported over a variable we manage, which is
+    // surprising enough to point out when SHADOWENV_WARN_MODIFIED is set.
+    let warn_modified = shadowenv.get_outer("SHADOWENV_WARN_MODIFIED");
+    if !matches!(warn_modified.as_deref(), None | Some("") | Some("0")) {
+        for name in shadowenv.modified_since_activation() {
+            shadowenv.add_warning(&format!(
+                "`{}` was changed since shadowenv last set it; that change may be overwritten",
+                name
+            ));
+        }
+    }
 
     match target {
         Some(target) => {
-            match ShadowLang::run_program(shadowenv, target) {
-                // no need to return anything descriptive here since we already
-                // had ketos print it to stderr.
-                Err(_) => Err(lang::ShadowlispError {}.into()),
+            let result = timed(timings.as_mut().map(|t| &mut t.eval), || {
+                ShadowLang::run_program(shadowenv, target)
+            });
+            match result {
+                Err(err) => Err(err),
+                // A subshell inherits both the activated env and $__shadowenv_data, but is
+                // forced to run on startup. If nothing would change, it's already active and
+                // there's nothing to re-announce. `exec` still has exec-only variables to set.
+                Ok(shadowenv) if inherited && !options.exec && is_already_applied(&shadowenv)? => {
+                    Ok(None)
+                }
+                // A `.shadowenv.d` holding nothing but comments has nothing to activate, and
+                // when nothing else was active either, nothing to undo or announce.
+                Ok(shadowenv) if active.is_none() && shadowenv.changes_nothing() => Ok(None),
                 Ok(shadowenv) => Ok(Some((shadowenv, true))),
             }
         }
@@ -107,99 +517,390 @@ pub fn load_env(
     }
 }
 
+/// Whether applying `shadowenv` is worth announcing, with the banner or an event. Leaving a project
+/// after `unload`, or unloading again, only changes the unload marker in $__shadowenv_data: there's
+/// no deactivation to announce.
+fn is_announced(shadowenv: &Shadowenv, activation: bool) -> Result<bool, Error> {
+    Ok(activation || !is_already_applied(shadowenv)?)
+}
+
+fn is_already_applied(shadowenv: &Shadowenv) -> Result<bool, Error> {
+    Ok(shadowenv
+        .exports()?
+        .keys()
+        .all(|varname| varname == "__shadowenv_data"))
+}
+
 /// Load a Source from the current dir, ensuring that it is trusted.
 fn load_trusted_source(pathbuf: PathBuf) -> Result<Option<Source>, Error> {
     if let Some(root) = loader::find_root(&pathbuf, loader::DEFAULT_RELATIVE_COMPONENT)? {
-        if !trust::is_dir_trusted(&root)? {
+        let source = loader::load_unresolved(&root)?;
+        if !trust::is_source_trusted(&root, &source)? {
             return Err(trust::NotTrusted {
                 not_trusted_dir_path: pathbuf.to_string_lossy().to_string(),
             }
             .into());
         }
-        return Ok(loader::load(root)?);
+        loader::check_pinned_version(&root, env!("CARGO_PKG_VERSION"))?;
+        let source = loader::resolve(source, root)?;
+        if let Some(source) = &source {
+            loader::check_required_version(source, env!("CARGO_PKG_VERSION"))?;
+        }
+        return Ok(source);
     }
     Ok(None)
 }
 
+/// How long a `cache-dir` directory has to go without being activated before
+/// `remove_stale_cache_dirs` removes it.
+const CACHE_DIR_MAX_IDLE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Remove the `cache-dir` and `mktempdir` directories of the previous activation which the new
+/// one doesn't use, along with any others left behind next to them. Other shells activating the
+/// same version of a project share its `cache-dir`s, and may still be using one we're done with:
+/// each activation refreshes the modification time of those it uses, so only those which no shell
+/// has activated for `CACHE_DIR_MAX_IDLE` are removed. A `mktempdir` directory belongs to one
+/// shell, so it's removed as soon as that shell is done with it, or is gone. Anything that doesn't
+/// look like one of ours, which could only come from a tampered-with $__shadowenv_data, is left
+/// alone, as are directories we fail to remove.
+fn remove_stale_cache_dirs(shadowenv: &Shadowenv) {
+    let in_use = shadowenv.cache_dirs();
+    let temp_root = shadowenv.temp_root();
+    let is_ours = |dir: &Path| is_cache_dir(dir) || temp_dir_shell(&temp_root, dir).is_some();
+    let mut candidates: BTreeSet<PathBuf> = BTreeSet::new();
+    for dir in shadowenv.stale_cache_dirs() {
+        let dir = PathBuf::from(dir);
+        if !is_ours(&dir) {
+            continue;
+        }
+        if let Some(Ok(entries)) = dir.parent().map(fs::read_dir) {
+            candidates.extend(
+                entries
+                    .filter_map(|entry| entry.ok())
+                    .map(|entry| entry.path())
+                    .filter(|path| is_ours(path)),
+            );
+        }
+        candidates.insert(dir);
+    }
+    for dir in candidates {
+        if in_use.contains(dir.to_string_lossy().as_ref()) {
+            continue;
+        }
+        let metadata = match fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.is_dir() => metadata,
+            _ => continue,
+        };
+        let stale = match temp_dir_shell(&temp_root, &dir) {
+            Some(pid) => pid == shadowenv.shell_pid() || !is_running(pid),
+            None => metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|idle| idle >= CACHE_DIR_MAX_IDLE),
+        };
+        if stale {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+}
+
+fn is_cache_dir(dir: &Path) -> bool {
+    let cache = dir.parent();
+    let shadowenv_d = cache.and_then(Path::parent);
+    dir.is_absolute()
+        && dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.split_once('-'))
+            .is_some_and(|(hash, _platform)| Hash::from_str(hash).is_ok())
+        && cache.and_then(Path::file_name) == Some(".cache".as_ref())
+        && shadowenv_d.and_then(Path::file_name) == Some(".shadowenv.d".as_ref())
+}
+
+/// The pid of the shell `dir` was handed out to, if it's a `mktempdir` directory in `temp_root`.
+fn temp_dir_shell(temp_root: &Path, dir: &Path) -> Option<u32> {
+    let name = dir.file_name()?.to_str()?;
+    let mut parts = name.rsplitn(3, '-');
+    let pid = parts.next()?.parse::<u32>().ok()?;
+    let hash = Hash::from_str(parts.next()?).ok()?;
+    match dir == crate::lang::temp_dir_path(temp_root, hash.hash, pid) {
+        true => Some(pid),
+        false => None,
+    }
+}
+
+/// Whether a process with this pid is still around, even if it isn't ours to signal.
+fn is_running(pid: u32) -> bool {
+    let pid = match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    let alive = unsafe { libc::kill(pid, 0) } == 0;
+    alive || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
 pub fn mutate_own_env(shadowenv: &Shadowenv) -> Result<(), Error> {
-    for (k, v) in shadowenv.exports()? {
-        match v {
+    for (k, export) in shadowenv.exports()? {
+        match export.value {
+            // shell variables aren't for child processes, and our env is only for them.
+            Some(_) if !export.exported => (),
             Some(s) => env::set_var(k, &s),
             None => env::remove_var(k),
         }
     }
+    for (k, value) in shadowenv.exec_only() {
+        match value {
+            Some(s) => env::set_var(k, s),
+            None => env::remove_var(k),
+        }
+    }
 
     Ok(())
 }
 
+/// Print the exports of a shadowenv for the calling shell. With `delta`, only the variables whose
+/// value differs from what the previous activation left behind are printed.
 pub fn apply_env(
     shadowenv: &Shadowenv,
     mode: VariableOutputMode,
     activation: bool,
+    delta: bool,
+) -> Result<(), Error> {
+    apply_env_to(
+        &mut io::stdout().lock(),
+        &mut io::stderr().lock(),
+        shadowenv,
+        &mode,
+        activation,
+        delta,
+        output::should_print_activation(),
+    )
+}
+
+/// Only `out` is evaluated by the shell, so it gets nothing but the exports. The banner and any
+/// warnings are for the user, and go to `err` whatever the mode.
+fn apply_env_to(
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+    shadowenv: &Shadowenv,
+    mode: &VariableOutputMode,
+    activation: bool,
+    delta: bool,
+    banner: bool,
 ) -> Result<(), Error> {
+    write_env(out, shadowenv, mode, delta)?;
+    let announce = banner && is_announced(shadowenv, activation)?;
     match mode {
-        VariableOutputMode::PosixMode => {
-            for (k, v) in shadowenv.exports()? {
-                match v {
-                    Some(s) => println!("export {}={}", k, shell_escape(&s)),
-                    None => println!("unset {}", k),
+        VariableOutputMode::PosixMode { .. }
+        | VariableOutputMode::FishMode
+        | VariableOutputMode::PowerShellMode { .. }
+            if announce =>
+        {
+            output::write_activation(err, activation, shadowenv.features(), shadowenv.notes())?;
+        }
+        _ => (),
+    }
+    output::write_warnings(err, shadowenv.warnings())?;
+    Ok(())
+}
+
+/// Write the exports of a shadowenv in the format expected by the given mode. Variables are always
+/// written in the same (sorted) order.
+pub fn write_env(
+    out: &mut dyn Write,
+    shadowenv: &Shadowenv,
+    mode: &VariableOutputMode,
+    delta: bool,
+) -> Result<(), Error> {
+    let exports = if delta {
+        shadowenv.delta_exports()?
+    } else {
+        shadowenv.exports()?
+    };
+    match mode {
+        VariableOutputMode::PosixMode { ansi_c_quotes } => {
+            let escape = |s: &str| match ansi_c_quotes {
+                true => posix_escape(s),
+                false => shell_escape(s),
+            };
+            for (k, export) in sorted(&exports) {
+                match &export.value {
+                    Some(s) if export.exported => writeln!(out, "export {}={}", k, escape(s))?,
+                    Some(s) => writeln!(out, "{}={}", k, escape(s))?,
+                    None => writeln!(out, "unset {}", k)?,
                 }
             }
-            output::print_activation_to_tty(activation, shadowenv.features());
         }
         VariableOutputMode::FishMode => {
-            for (k, v) in shadowenv.exports()? {
-                match v {
+            for (k, export) in sorted(&exports) {
+                let scope = if export.exported { "-gx" } else { "-gu" };
+                match &export.value {
                     Some(s) => {
                         if k == "PATH" {
-                            let pathlist = shell_escape(&s).replace(":", "' '");
-                            println!("set -gx {} {}", k, pathlist);
+                            let pathlist = shell_escape(s).replace(":", "' '");
+                            writeln!(out, "set {} {} {}", scope, k, pathlist)?;
                         } else {
-                            println!("set -gx {} {}", k, shell_escape(&s));
+                            writeln!(out, "set {} {} {}", scope, k, shell_escape(s))?;
                         }
                     }
                     None => {
-                        println!("set -e {}", k);
+                        writeln!(out, "set -e {}", k)?;
                     }
                 }
             }
-            output::print_activation_to_tty(activation, shadowenv.features());
         }
-        VariableOutputMode::PorcelainMode => {
+        VariableOutputMode::PowerShellMode { path_separator } => {
+            // PowerShell's own variables aren't visible to the hook at all, so unexported
+            // variables are set in the environment like any other.
+            for (k, export) in sorted(&exports) {
+                match &export.value {
+                    Some(s) if k == "PATH" => {
+                        let pathlist = s.replace(':', &path_separator.to_string());
+                        writeln!(out, "$env:{} = {}", k, powershell_escape(&pathlist))?;
+                    }
+                    Some(s) => writeln!(out, "$env:{} = {}", k, powershell_escape(s))?,
+                    None => writeln!(out, "Remove-Item -ErrorAction SilentlyContinue Env:{}", k)?,
+                }
+            }
+        }
+        VariableOutputMode::PorcelainMode { .. } => {
             // three fields: <operation> : <name> : <value>
-            // opcodes: 1: set, unexported (unused)
+            // opcodes: 0: end of stream (name and value are empty); only with --end-marker, and
+            //             then last whatever happened (see `end_porcelain`)
+            //          1: set, unexported
             //          2: set, exported
             //          3: unset (value is empty)
+            //          4: trust required (name is the directory, value the command to run in
+            //             it); only ever printed on its own, when the hook fails for that reason
             // field separator is 0x1F; record separator is 0x1E. There's a trailing record
-            // separator because I'm lazy but don't depend on it not going away.
-            for (k, v) in shadowenv.exports()? {
-                match v {
-                    Some(s) => print!("\x02\x1F{}\x1F{}\x1E", k, s),
-                    None => print!("\x03\x1F{}\x1F\x1E", k),
+            // separator because I'm lazy but don't depend on it not going away: consumers that
+            // can't wait for EOF should ask for the end marker instead.
+            for (k, export) in sorted(&exports) {
+                match &export.value {
+                    Some(s) if export.exported => write!(out, "\x02\x1F{}\x1F{}\x1E", k, s)?,
+                    Some(s) => write!(out, "\x01\x1F{}\x1F{}\x1E", k, s)?,
+                    None => write!(out, "\x03\x1F{}\x1F\x1E", k)?,
                 }
             }
         }
         VariableOutputMode::JsonMode => {
-            let modifs = Modifications::new(shadowenv.exports()?);
-            println!("{}", serde_json::to_string(&modifs).unwrap());
+            let modifs = Modifications::new(exports);
+            writeln!(out, "{}", serde_json::to_string(&modifs).unwrap())?;
         }
         VariableOutputMode::PrettyJsonMode => {
-            let modifs = Modifications::new(shadowenv.exports()?);
-            println!("{}", serde_json::to_string_pretty(&modifs).unwrap());
+            let modifs = Modifications::new(exports);
+            writeln!(out, "{}", serde_json::to_string_pretty(&modifs).unwrap())?;
         }
     }
     Ok(())
 }
 
+fn sorted(exports: &HashMap<String, Export>) -> BTreeMap<&String, &Export> {
+    exports.iter().collect()
+}
+
 fn shell_escape(s: &str) -> String {
     shell::escape(Cow::from(s)).to_string()
 }
 
+/// Quote a string for the shells reading ANSI-C quoting (bash, zsh and the kshs). Plain single
+/// quotes keep control characters intact, but a value spanning several lines is easily mangled by
+/// whatever passes our output to `eval`, so values holding any are written with ANSI-C quoting
+/// (`$'...'`) instead, which keeps each assignment on one line. Other POSIX shells get the plain
+/// quotes, newlines and all: until POSIX.1-2024, `$'...'` was a `$` and a quoted string to them.
+fn posix_escape(s: &str) -> String {
+    if !s.chars().any(|c| c.is_ascii_control()) {
+        return shell_escape(s);
+    }
+    let mut escaped = String::with_capacity(s.len() + 3);
+    escaped.push_str("$'");
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\'' => escaped.push_str("\\'"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('\'');
+    escaped
+}
+
+/// Quote a string for PowerShell: nothing is special inside single quotes, except single quotes
+/// themselves (including the typographic ones PowerShell also accepts), which are doubled.
+fn powershell_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('\'');
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            escaped.push(c);
+        }
+        escaped.push(c);
+    }
+    escaped.push('\'');
+    escaped
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hash::SourceFile;
+    use crate::testing;
+    use std::collections::BTreeMap;
     use std::fs;
     use tempfile::tempdir;
+
+    const FORCED: LoadOptions = LoadOptions {
+        force: true,
+        login: false,
+        exec: false,
+        shellpid: None,
+    };
+
+    fn build_source(content: &str) -> Source {
+        Source {
+            dir: "dir".to_string(),
+            files: vec![SourceFile {
+                name: "file.lisp".to_string(),
+                contents: content.to_string(),
+            }],
+            includes: BTreeMap::new(),
+            data_files: BTreeMap::new(),
+        }
+    }
+
+    /// Load `target` over `env` and `shadowenv_data` as the hook does.
+    fn load(
+        target: Option<Source>,
+        shadowenv_data: String,
+        env: HashMap<String, String>,
+        options: LoadOptions,
+    ) -> Result<Option<(Shadowenv, bool)>, Error> {
+        load_env_from_source(target, shadowenv_data, env, options, &mut None)
+    }
+
+    /// What a forced hook activates for `source`, over `env` and `shadowenv_data`.
+    fn activate(source: Source, shadowenv_data: String, env: HashMap<String, String>) -> Shadowenv {
+        let (shadowenv, _) = load(Some(source), shadowenv_data, env, FORCED)
+            .unwrap()
+            .expect("nothing was activated");
+        shadowenv
+    }
+
+    /// The env a subshell would inherit after the given activation was applied.
+    fn applied_env(shadowenv: &Shadowenv) -> HashMap<String, String> {
+        shadowenv
+            .exports()
+            .unwrap()
+            .into_iter()
+            .filter_map(|(k, export)| export.value.map(|v| (k, v)))
+            .collect()
+    }
+
     #[test]
     fn load_trusted_source_returns_an_error_for_untrusted_folders() {
         let temp_dir = tempdir().unwrap().into_path();
@@ -209,4 +910,1155 @@ mod tests {
         assert!(result.is_err());
         assert_eq!(format!("directory: '{}' contains untrusted shadowenv program: `shadowenv help trust` to learn more.", path), result.err().unwrap().to_string())
     }
+
+    #[test]
+    fn comments_only_source_is_not_activated() {
+        let source = build_source(";; nothing here yet\n\n  ; (env/set \"A\" \"1\")\n");
+        let result = load(
+            Some(source.clone()),
+            "".to_string(),
+            HashMap::new(),
+            LoadOptions::default(),
+        )
+        .unwrap();
+        assert!(result.is_none());
+
+        // leaving another project for it still undoes that project's changes.
+        let other = activate(
+            build_source(r#"(env/set "A" "1")"#),
+            "".to_string(),
+            HashMap::new(),
+        );
+        let env = applied_env(&other);
+        let data = env["__shadowenv_data"].clone();
+        let (shadowenv, activated) = load(Some(source), data, env, LoadOptions::default())
+            .unwrap()
+            .unwrap();
+        assert!(activated);
+        assert_eq!(shadowenv.exports().unwrap()["A"].value, None);
+
+        // a program which only provides features or warns is still worth announcing.
+        for program in &[r#"(provide "ruby")"#, r#"(warn "no ruby found")"#] {
+            let result = load(
+                Some(build_source(program)),
+                "".to_string(),
+                HashMap::new(),
+                LoadOptions::default(),
+            )
+            .unwrap();
+            assert!(result.is_some(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn data_can_be_left_unexported() {
+        let mut shadowenv = activate(
+            build_source(r#"(env/set "VAR_A" "a")"#),
+            "".to_string(),
+            HashMap::new(),
+        );
+        let render = |shadowenv: &Shadowenv, mode: VariableOutputMode| {
+            let mut out: Vec<u8> = vec![];
+            write_env(&mut out, shadowenv, &mode, false).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let posix = render(
+            &shadowenv,
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+        );
+        assert!(posix.contains("export __shadowenv_data='"));
+        let fish = render(&shadowenv, VariableOutputMode::FishMode);
+        assert!(fish.contains("set -gx __shadowenv_data '"));
+
+        shadowenv.set_export_data(false);
+        let posix = render(
+            &shadowenv,
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+        );
+        assert!(posix.contains("\n__shadowenv_data='"));
+        assert!(posix.contains("export VAR_A=a\n"));
+        let fish = render(&shadowenv, VariableOutputMode::FishMode);
+        assert!(fish.contains("set -gu __shadowenv_data '"));
+        assert!(fish.contains("set -gx VAR_A a\n"));
+    }
+
+    #[test]
+    fn events_are_written_to_the_given_fd() {
+        use std::io::Read;
+        use std::os::unix::io::FromRawFd;
+
+        let (shadowenv, activated) = load(
+            Some(build_source(r#"(provide "ruby" "3.1.2") (provide "node")"#)),
+            "".to_string(),
+            HashMap::new(),
+            LoadOptions::default(),
+        )
+        .unwrap()
+        .unwrap();
+        let env = applied_env(&shadowenv);
+        let data = env["__shadowenv_data"].clone();
+        let (left, deactivated) = load(None, data, env, LoadOptions::default())
+            .unwrap()
+            .unwrap();
+
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        write_event(fds[1], &shadowenv, activated).unwrap();
+        write_event(fds[1], &left, deactivated).unwrap();
+        // write_event leaves the fd open, so close it for the read to see the end.
+        drop(unsafe { fs::File::from_raw_fd(fds[1]) });
+        let mut events = String::new();
+        unsafe { fs::File::from_raw_fd(fds[0]) }
+            .read_to_string(&mut events)
+            .unwrap();
+
+        assert_eq!(
+            events,
+            format!(
+                "{{\"event\":\"activated\",\"hash\":\"{}\",\"features\":[\"node\",\"ruby:3.1.2\"]}}\n{{\"event\":\"deactivated\"}}\n",
+                shadowenv.target_hash()
+            )
+        );
+    }
+
+    #[test]
+    fn inherited_activation_is_not_reapplied() {
+        let source = build_source(r#"(env/set "VAR_A" "a")"#);
+        let (shadowenv, activated) =
+            load(Some(source.clone()), "".to_string(), HashMap::new(), FORCED)
+                .unwrap()
+                .unwrap();
+        assert!(activated);
+
+        let env = applied_env(&shadowenv);
+        let data = env["__shadowenv_data"].clone();
+        let result = load(Some(source), data, env, FORCED).unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn nested_shells_do_not_stack_changes() {
+        let mut source = build_source(
+            r#"
+                (env/prepend-to-pathlist "PATH" "/project/bin")
+                (env/set "GEM_HOME" (path-concat (env/get "HOME") ".gem"))
+            "#,
+        );
+        source.add_file(
+            "200_later.lisp".to_string(),
+            r#"(env/append-to-pathlist "PATH" "/project/tools")"#.to_string(),
+        );
+        let mut env: HashMap<String, String> = vec![("PATH", "/usr/bin"), ("HOME", "/home/me")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let shadowenv = activate(source.clone(), "".to_string(), env.clone());
+        for (k, export) in shadowenv.exports().unwrap() {
+            env.insert(k, export.value.unwrap());
+        }
+
+        assert_eq!(env["PATH"], "/project/bin:/usr/bin:/project/tools");
+
+        // a nested shell forces a run on startup, inheriting everything the outer one had.
+        let nested = |source: Source| {
+            let data = env["__shadowenv_data"].clone();
+            match load(Some(source), data, env.clone(), FORCED).unwrap() {
+                Some((shadowenv, _)) => Some(apply(&env, shadowenv.exports().unwrap())),
+                None => None,
+            }
+        };
+        assert!(nested(source.clone()).is_none());
+
+        // a changed version of the project is re-applied over the outer env, not stacked on top.
+        source.add_file(
+            "300_more.lisp".to_string(),
+            r#"(env/append-to-pathlist "PATH" "/project/more")"#.to_string(),
+        );
+        let changed = nested(source).unwrap();
+        assert_eq!(
+            changed["PATH"],
+            "/project/bin:/usr/bin:/project/tools:/project/more"
+        );
+        assert_eq!(changed["GEM_HOME"], "/home/me/.gem");
+    }
+
+    #[test]
+    fn inherited_activation_is_reapplied_if_env_differs() {
+        let source = build_source(r#"(env/set "VAR_A" "a")"#);
+        let shadowenv = activate(source.clone(), "".to_string(), HashMap::new());
+
+        let mut env = applied_env(&shadowenv);
+        let data = env["__shadowenv_data"].clone();
+        env.remove("VAR_A");
+        let (shadowenv, activated) = load(Some(source), data, env, FORCED).unwrap().unwrap();
+        assert!(activated);
+        assert_eq!(shadowenv.get("VAR_A"), Some("a".to_string()));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_values_are_left_alone() {
+        use std::os::unix::ffi::OsStringExt;
+
+        let vars = vec![
+            (OsString::from("PATH"), OsString::from("/usr/bin")),
+            (
+                OsString::from("LATIN1"),
+                OsString::from_vec(b"caf\xe9".to_vec()),
+            ),
+            (
+                OsString::from("CHANGED"),
+                OsString::from_vec(b"\xff".to_vec()),
+            ),
+            (
+                OsString::from("MANPATH"),
+                OsString::from_vec(b"/opt/caf\xe9/man:/usr/share/man".to_vec()),
+            ),
+        ];
+        let non_utf8 = shadowenv::non_utf8_vars(vars.iter());
+        let env = shadowenv::env_from_os(vars.into_iter());
+        let source = build_source(
+            r#"
+                (env/prepend-to-pathlist "PATH" "/opt/bin")
+                (env/prepend-to-pathlist "MANPATH" "/opt/man")
+                (env/set "CHANGED" (concat (env/get "CHANGED") "!"))
+            "#,
+        );
+        let mut shadowenv = activate(source, "".to_string(), env);
+        shadowenv.leave_alone(non_utf8);
+
+        let exports = shadowenv.exports().unwrap();
+        assert!(!exports.contains_key("LATIN1"));
+        assert!(!exports.contains_key("CHANGED"));
+        assert!(!exports.contains_key("MANPATH"));
+        assert_eq!(exports["PATH"].value, Some("/opt/bin:/usr/bin".to_string()));
+
+        let data = shadowenv.shadowenv_data();
+        assert_eq!(data.scalars, vec![]);
+        assert_eq!(data.lists.len(), 1);
+        assert_eq!(
+            shadowenv.warnings(),
+            &[
+                "`CHANGED` isn't valid UTF-8, so shadowenv left it as it was".to_string(),
+                "`MANPATH` isn't valid UTF-8, so shadowenv left it as it was".to_string(),
+            ]
+        );
+    }
+
+    fn apply(
+        env: &HashMap<String, String>,
+        exports: HashMap<String, Export>,
+    ) -> HashMap<String, String> {
+        let mut env = env.clone();
+        for (k, export) in exports {
+            match export.value {
+                Some(v) => env.insert(k, v),
+                None => env.remove(&k),
+            };
+        }
+        env
+    }
+
+    #[test]
+    fn delta_exports_end_up_in_the_same_state() {
+        let base: HashMap<String, String> = vec![("PATH", "/usr/bin"), ("KEPT", "x")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let first = build_source(
+            r#"
+                (env/set "SAME" "1")
+                (env/set "CHANGED" "1")
+                (env/set "DROPPED" "1")
+                (env/prepend-to-pathlist "PATH" "/first/bin")
+            "#,
+        );
+        let second = build_source(
+            r#"
+                (env/set "SAME" "1")
+                (env/set "CHANGED" "2")
+                (env/set "ADDED" "2")
+                (env/prepend-to-pathlist "PATH" "/second/bin")
+            "#,
+        );
+
+        let shadowenv = activate(first, "".to_string(), base.clone());
+        let active = apply(&base, shadowenv.exports().unwrap());
+        let data = active["__shadowenv_data"].clone();
+
+        // a shell inherits the activated env; a porcelain consumer may only have kept the data.
+        for inherited in &[active.clone(), base.clone()] {
+            for target in &[Some(second.clone()), None] {
+                let (shadowenv, _) = load(target.clone(), data.clone(), inherited.clone(), FORCED)
+                    .unwrap()
+                    .unwrap();
+                let delta = shadowenv.delta_exports().unwrap();
+                if target.is_some() {
+                    assert!(!delta.contains_key("SAME"));
+                    assert!(!delta.contains_key("KEPT"));
+                }
+                assert_eq!(
+                    apply(&active, delta),
+                    apply(inherited, shadowenv.exports().unwrap())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn source_hash_is_stable_and_matches_shadowenv_data() {
+        let program = r#"(env/set "ENV_FINGERPRINT" (source-hash))"#;
+        let load = |source: Source, env: HashMap<String, String>| {
+            let shadowenv = activate(source, "".to_string(), env);
+            shadowenv
+        };
+
+        let shadowenv = load(build_source(program), HashMap::new());
+        let hash = shadowenv.get("ENV_FINGERPRINT").unwrap();
+        assert_eq!(hash, Hash::from_str(&hash).unwrap().to_string());
+        let mut env = applied_env(&shadowenv);
+        assert!(env["__shadowenv_data"].starts_with(&format!("{}:", hash)));
+
+        // re-running the same source, even in the env the first run produced, gives the same value.
+        env.insert("ENV_FINGERPRINT".to_string(), "stale".to_string());
+        let again = load(build_source(program), env);
+        assert_eq!(again.get("ENV_FINGERPRINT"), Some(hash.clone()));
+
+        let changed = load(build_source(&format!("{}\n", program)), HashMap::new());
+        assert_ne!(changed.get("ENV_FINGERPRINT"), Some(hash));
+    }
+
+    #[test]
+    fn unload_restores_the_outer_env() {
+        let base: HashMap<String, String> = vec![("PATH", "/usr/bin"), ("REPLACED", "outer")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let source = build_source(
+            r#"
+                (env/set "REPLACED" "inner")
+                (env/set "ADDED" "inner")
+                (env/set-unexported "SHELL_ONLY" "inner")
+                (env/prepend-to-pathlist "PATH" "/project/bin")
+            "#,
+        );
+        let shadowenv = activate(source, "".to_string(), base.clone());
+        let active = apply(&base, shadowenv.exports().unwrap());
+        assert_eq!(active["ADDED"], "inner");
+
+        let shadowenv = load_unloaded_env(active["__shadowenv_data"].clone(), active.clone())
+            .unwrap()
+            .unwrap();
+        let exports = shadowenv.exports().unwrap();
+        assert_eq!(exports["SHELL_ONLY"].value, None);
+        let mut unloaded = apply(&active, exports);
+        let data = unloaded.remove("__shadowenv_data").unwrap();
+        assert!(data.starts_with("0000000000000000:"));
+        let hash = active["__shadowenv_data"][..16].to_string();
+        assert_eq!(
+            undo::Data::from_str(&data[17..]).unwrap(),
+            undo::Data {
+                unloaded: Some(hash),
+                ..undo::Data::new()
+            }
+        );
+        assert_eq!(unloaded, base);
+
+        // with nothing active, there's nothing to undo.
+        assert!(load_unloaded_env("".to_string(), base.clone())
+            .unwrap()
+            .is_none());
+        assert!(load_unloaded_env("0000000000000000:{}".to_string(), base)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn unloaded_projects_stay_inactive_until_left() {
+        let program = r#"(env/set "GREETING" "hi")"#;
+        let base: HashMap<String, String> = HashMap::new();
+        let hook = |source: Option<Source>, env: &HashMap<String, String>, force: bool| {
+            let data = env.get("__shadowenv_data").cloned().unwrap_or_default();
+            load(
+                source,
+                data,
+                env.clone(),
+                LoadOptions {
+                    force,
+                    ..LoadOptions::default()
+                },
+            )
+            .unwrap()
+            .map(|(shadowenv, _)| apply(env, shadowenv.exports().unwrap()))
+        };
+
+        let active = hook(Some(build_source(program)), &base, false).unwrap();
+        let shadowenv = load_unloaded_env(active["__shadowenv_data"].clone(), active.clone())
+            .unwrap()
+            .unwrap();
+        let unloaded = apply(&active, shadowenv.exports().unwrap());
+        assert_eq!(unloaded.get("GREETING"), None);
+
+        // the next prompts, even a subshell starting up, leave it alone.
+        assert!(hook(Some(build_source(program)), &unloaded, false).is_none());
+        assert!(hook(Some(build_source(program)), &unloaded, true).is_none());
+        // unloading again changes nothing.
+        let again = load_unloaded_env(unloaded["__shadowenv_data"].clone(), unloaded.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(apply(&unloaded, again.exports().unwrap()), unloaded);
+
+        // a changed version of the project is activated as usual.
+        let changed = hook(
+            Some(build_source("(env/set \"GREETING\" \"hey\")")),
+            &unloaded,
+            false,
+        );
+        assert_eq!(changed.unwrap()["GREETING"], "hey");
+
+        // leaving drops the marker, and nothing else, so coming back activates it again.
+        let left = hook(None, &unloaded, false).unwrap();
+        assert_eq!(left.get("GREETING"), None);
+        assert_eq!(
+            left["__shadowenv_data"],
+            "0000000000000000:{\"scalars\":[],\"lists\":[]}"
+        );
+        let back = hook(Some(build_source(program)), &left, false).unwrap();
+        assert_eq!(back["GREETING"], "hi");
+        assert!(hook(None, &hook(None, &back, false).unwrap(), false).is_none());
+    }
+
+    #[test]
+    fn deactivation_exports_restore_originals() {
+        let base: HashMap<String, String> = vec![
+            ("PATH", "/usr/bin:/bin"),
+            ("REPLACED", "outer"),
+            ("UNTOUCHED", "outer"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let source = build_source(
+            r#"
+                (env/set "REPLACED" "inner")
+                (env/set "ADDED" "inner")
+                (env/prepend-to-pathlist "PATH" "/project/bin")
+            "#,
+        );
+        let shadowenv = activate(source.clone(), "".to_string(), base.clone());
+        let mut active = apply(&base, shadowenv.exports().unwrap());
+        active.insert(
+            "PATH".to_string(),
+            format!("/home/me/bin:{}", active["PATH"]),
+        );
+
+        let exports =
+            deactivation_exports(active["__shadowenv_data"].clone(), active.clone()).unwrap();
+        let mut names: Vec<&str> = exports.keys().map(String::as_str).collect();
+        names.sort();
+        assert_eq!(names, vec!["ADDED", "PATH", "REPLACED", "__shadowenv_data"]);
+        assert_eq!(exports["REPLACED"].value, Some("outer".to_string()));
+        assert_eq!(exports["ADDED"].value, None);
+        assert_eq!(
+            exports["PATH"].value,
+            Some("/home/me/bin:/usr/bin:/bin".to_string())
+        );
+        assert!(exports["PATH"].exported);
+
+        // the data left behind marks this version of the project as unloaded, so that a shell
+        // applying the exports isn't activated again by its next hook run, even a forced one.
+        let hash = source.hash().unwrap();
+        let data = exports["__shadowenv_data"].value.clone().unwrap();
+        let (_, json) = data.split_once(':').unwrap();
+        let marker = undo::Data::from_str(json).unwrap().unloaded;
+        assert_eq!(marker, Some(Hash { hash }.to_string()));
+        let unloaded = apply(&active, exports);
+        for force in [false, true] {
+            let loaded = load(
+                Some(source.clone()),
+                data.clone(),
+                unloaded.clone(),
+                LoadOptions {
+                    force,
+                    ..LoadOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(loaded.is_none());
+        }
+
+        assert!(deactivation_exports("".to_string(), base)
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Make `dir` look like no activation has used it for longer than `CACHE_DIR_MAX_IDLE`.
+    fn idle(dir: &Path) {
+        let long_ago = SystemTime::now() - CACHE_DIR_MAX_IDLE - Duration::from_secs(60);
+        fs::File::open(dir).unwrap().set_modified(long_ago).unwrap();
+    }
+
+    #[test]
+    fn stale_cache_dirs_are_removed() {
+        let temp_dir = fs::canonicalize(tempdir().unwrap().into_path()).unwrap();
+        let cache = temp_dir.join(".shadowenv.d/.cache");
+        let stale = cache.join(crate::lang::cache_dir_name(1));
+        let current = cache.join(crate::lang::cache_dir_name(2));
+        let recent = cache.join(crate::lang::cache_dir_name(3));
+        let leftover = cache.join(crate::lang::cache_dir_name(4));
+        let unrelated = temp_dir.join("unrelated");
+        for dir in &[&stale, &current, &recent, &leftover, &unrelated] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        for dir in &[&stale, &current, &leftover, &unrelated] {
+            idle(dir);
+        }
+
+        let mut data = undo::Data::new();
+        data.cache_dirs = [&stale, &current, &recent, &unrelated]
+            .iter()
+            .map(|d| d.to_string_lossy().to_string())
+            .collect();
+        let mut shadowenv = Shadowenv::new(HashMap::new(), data, 2);
+        shadowenv.add_cache_dir(&current.to_string_lossy());
+        remove_stale_cache_dirs(&shadowenv);
+
+        assert!(!stale.exists());
+        // one the previous activation didn't know about, left behind by another shell.
+        assert!(!leftover.exists());
+        assert!(current.is_dir());
+        // another shell activated that version lately, and may still be using it.
+        assert!(recent.is_dir());
+        assert!(unrelated.is_dir());
+    }
+
+    #[test]
+    fn shell_names_map_to_output_modes() {
+        for shell in &["fish", "/usr/local/bin/fish", "-fish"] {
+            assert!(matches!(
+                shell_output_mode(shell),
+                Some(VariableOutputMode::FishMode)
+            ));
+        }
+        for shell in &["pwsh", "/opt/microsoft/powershell/7/pwsh", "powershell.exe"] {
+            assert!(matches!(
+                shell_output_mode(shell),
+                Some(VariableOutputMode::PowerShellMode { .. })
+            ));
+        }
+        for shell in &["bash", "/bin/zsh", "-zsh", "ksh\n"] {
+            assert!(matches!(
+                shell_output_mode(shell),
+                Some(VariableOutputMode::PosixMode {
+                    ansi_c_quotes: true
+                })
+            ));
+        }
+        for shell in &["sh", "/bin/dash", "ash"] {
+            assert!(matches!(
+                shell_output_mode(shell),
+                Some(VariableOutputMode::PosixMode {
+                    ansi_c_quotes: false
+                })
+            ));
+        }
+        // no nushell output format yet, so it's left to the fallback like anything else unknown.
+        for shell in &["nu", "tcsh", "python3", "", "/"] {
+            assert!(shell_output_mode(shell).is_none());
+        }
+    }
+
+    #[test]
+    fn event_fd_is_an_open_descriptor_past_stderr() {
+        use std::os::unix::io::AsRawFd;
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        assert_eq!(event_fd(&fd.to_string()), Some(fd));
+        for value in &["0", "1", "2", "-1", "", "events", "2147483647"] {
+            assert_eq!(event_fd(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn temp_dirs_are_removed_on_deactivation() {
+        let temp_root = tempdir().unwrap();
+        let env: HashMap<String, String> = vec![(
+            "TMPDIR".to_string(),
+            temp_root.path().to_string_lossy().to_string(),
+        )]
+        .into_iter()
+        .collect();
+        let source = build_source(r#"(env/set "SCRATCH" (mktempdir))"#);
+        let options = LoadOptions {
+            shellpid: Some(4242),
+            ..FORCED
+        };
+        let (shadowenv, _) = load(Some(source), "".to_string(), env.clone(), options)
+            .unwrap()
+            .unwrap();
+        let mut active = env;
+        active.extend(applied_env(&shadowenv));
+        let dir = PathBuf::from(&active["SCRATCH"]);
+        assert!(dir.is_dir());
+
+        // those of other shells, one still running (this one) and one which isn't.
+        let hash = shadowenv.target_hash().hash;
+        let running = crate::lang::temp_dir_path(temp_root.path(), hash, std::process::id());
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let gone = crate::lang::temp_dir_path(temp_root.path(), hash, child.id());
+        for dir in &[&running, &gone] {
+            fs::create_dir(dir).unwrap();
+        }
+
+        let mut shadowenv = load_unloaded_env(active["__shadowenv_data"].clone(), active)
+            .unwrap()
+            .unwrap();
+        shadowenv.set_shell_pid(4242);
+        remove_stale_cache_dirs(&shadowenv);
+        assert!(!dir.exists());
+        assert!(running.is_dir());
+        assert!(!gone.exists());
+
+        // only directories we could have handed out are removed.
+        let unrelated = tempdir().unwrap();
+        assert_eq!(temp_dir_shell(temp_root.path(), unrelated.path()), None);
+        assert_eq!(
+            temp_dir_shell(
+                temp_root.path(),
+                &unrelated.path().join(running.file_name().unwrap())
+            ),
+            None
+        );
+        assert_eq!(
+            temp_dir_shell(temp_root.path(), &running),
+            Some(std::process::id())
+        );
+    }
+
+    #[test]
+    fn warns_about_managed_vars_changed_by_the_user() {
+        let program = r#"
+            (env/set "MANAGED" "shadowenv")
+            (env/set "UNTOUCHED" "shadowenv")
+            (env/prepend-to-pathlist "PATH" "/project/bin")
+        "#;
+        let base: HashMap<String, String> = vec![("PATH", "/usr/bin")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let shadowenv = activate(build_source(program), "".to_string(), base.clone());
+        let mut env = apply(&base, shadowenv.exports().unwrap());
+        let data = env["__shadowenv_data"].clone();
+        env.insert("MANAGED".to_string(), "user".to_string());
+        env.insert("PATH".to_string(), "/usr/bin".to_string());
+
+        let reload = |env: HashMap<String, String>| {
+            let shadowenv = activate(build_source(program), data.clone(), env);
+            shadowenv.warnings().to_vec()
+        };
+
+        assert!(reload(env.clone()).is_empty());
+
+        env.insert("SHADOWENV_WARN_MODIFIED".to_string(), "1".to_string());
+        assert_eq!(
+            reload(env),
+            vec![
+                "`MANAGED` was changed since shadowenv last set it; that change may be overwritten",
+                "`PATH` was changed since shadowenv last set it; that change may be overwritten",
+            ]
+        );
+    }
+
+    #[test]
+    fn load_multi_reports_each_directory() {
+        let temp_dir = tempdir().unwrap().into_path();
+        let plain = temp_dir.join("plain");
+        let untrusted = temp_dir.join("untrusted");
+        let missing = temp_dir.join("missing");
+        fs::create_dir(&plain).unwrap();
+        fs::create_dir_all(untrusted.join(".shadowenv.d")).unwrap();
+
+        let results =
+            load_multi(vec![plain.clone(), untrusted.clone(), missing.clone()], "").unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[plain.to_string_lossy().as_ref()],
+            serde_json::Value::Null
+        );
+        assert_eq!(
+            results[untrusted.to_string_lossy().as_ref()]["error"]["kind"],
+            "not_trusted"
+        );
+        assert_eq!(
+            results[missing.to_string_lossy().as_ref()]["error"]["kind"],
+            "other"
+        );
+    }
+
+    #[test]
+    fn exec_in_an_activated_shell_gets_exec_only_vars() {
+        let source = build_source(
+            r#"
+                (env/prepend-to-pathlist "PATH" "/project/bin")
+                (env/set-exec-only "DEBUG" "1")
+            "#,
+        );
+        let base: HashMap<String, String> = vec![("PATH", "/usr/bin")]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let load = |data: String, env: HashMap<String, String>, exec: bool| {
+            load(
+                Some(source.clone()),
+                data,
+                env,
+                LoadOptions { exec, ..FORCED },
+            )
+            .unwrap()
+        };
+        let (shadowenv, _) = load("".to_string(), base.clone(), false).unwrap();
+        let active = apply(&base, shadowenv.exports().unwrap());
+        let data = active["__shadowenv_data"].clone();
+
+        // the shell itself has nothing left to do.
+        assert!(load(data.clone(), active.clone(), false).is_none());
+
+        // but a command run with `exec` from it gets the exec-only variables, on top of the env
+        // the shell already has.
+        let (shadowenv, _) = load(data, active.clone(), true).unwrap();
+        assert_eq!(
+            shadowenv.exec_only().get("DEBUG"),
+            Some(&Some("1".to_string()))
+        );
+        let exec_env = apply(&active, shadowenv.exports().unwrap());
+        assert_eq!(exec_env["PATH"], "/project/bin:/usr/bin");
+        assert!(!exec_env.contains_key("DEBUG"));
+    }
+
+    #[test]
+    fn exec_only_vars_reach_exec_but_not_the_shell() {
+        let source = build_source(
+            r#"
+                (env/set "SHADOWENV_TEST_INTERACTIVE" "yes")
+                (env/set-exec-only "SHADOWENV_TEST_EXEC_ONLY" "debug")
+            "#,
+        );
+        let shadowenv = activate(source, "".to_string(), HashMap::new());
+
+        let hook_output = testing::render(
+            &shadowenv,
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+        );
+        assert!(hook_output.contains("SHADOWENV_TEST_INTERACTIVE"));
+        assert!(!hook_output.contains("SHADOWENV_TEST_EXEC_ONLY"));
+        assert!(!shadowenv
+            .exports()
+            .unwrap()
+            .contains_key("SHADOWENV_TEST_EXEC_ONLY"));
+
+        mutate_own_env(&shadowenv).unwrap();
+        assert_eq!(env::var("SHADOWENV_TEST_EXEC_ONLY").unwrap(), "debug");
+        assert_eq!(env::var("SHADOWENV_TEST_INTERACTIVE").unwrap(), "yes");
+        env::remove_var("SHADOWENV_TEST_EXEC_ONLY");
+        env::remove_var("SHADOWENV_TEST_INTERACTIVE");
+        env::remove_var("__shadowenv_data");
+
+        // a program setting nothing else still has something to activate.
+        let source = build_source(r#"(env/set-exec-only "SHADOWENV_TEST_EXEC_ONLY" "debug")"#);
+        let loaded = load(Some(source), "".to_string(), HashMap::new(), FORCED).unwrap();
+        assert!(loaded.is_some());
+    }
+
+    #[test]
+    fn notes_are_shown_with_the_banner_only() {
+        let source = build_source(
+            r#"
+                (info "Using staging DB")
+                (env/set "DATABASE_URL" "postgres://staging")
+                (info "Run bin/setup first")
+            "#,
+        );
+        let (shadowenv, activation) = load(Some(source), "".to_string(), HashMap::new(), FORCED)
+            .unwrap()
+            .unwrap();
+        let run = |mode: VariableOutputMode, banner: bool| {
+            let mut out: Vec<u8> = vec![];
+            let mut err: Vec<u8> = vec![];
+            apply_env_to(
+                &mut out, &mut err, &shadowenv, &mode, activation, false, banner,
+            )
+            .unwrap();
+            (
+                String::from_utf8(out).unwrap(),
+                String::from_utf8(err).unwrap(),
+            )
+        };
+
+        let (out, err) = run(
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+            true,
+        );
+        assert!(!out.contains("staging DB"));
+        assert!(out.lines().all(|line| line.starts_with("export ")));
+        let lines: Vec<&str> = err.lines().collect();
+        assert!(lines[0].contains("activated"));
+        assert!(lines[1].contains("Using staging DB"));
+        assert!(lines[2].contains("Run bin/setup first"));
+        assert!(!err.contains("warning"));
+
+        // notes belong to the banner, so they go wherever it goes.
+        let (_, err) = run(
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+            false,
+        );
+        assert!(!err.contains("staging DB"));
+        let (out, err) = run(VariableOutputMode::JsonMode, true);
+        assert!(!out.contains("staging DB"));
+        assert!(!err.contains("staging DB"));
+    }
+
+    #[test]
+    fn banner_stays_out_of_every_modes_output() {
+        let source = build_source(
+            r#"
+                (provide "ruby" "3.1.2")
+                (env/set "VAR_A" "a")
+                (warn "careful")
+            "#,
+        );
+        let (shadowenv, activation) = load(Some(source), "".to_string(), HashMap::new(), FORCED)
+            .unwrap()
+            .unwrap();
+
+        for (name, mode, shell) in [
+            (
+                "posix",
+                VariableOutputMode::PosixMode {
+                    ansi_c_quotes: true,
+                },
+                true,
+            ),
+            ("fish", VariableOutputMode::FishMode, true),
+            (
+                "powershell",
+                VariableOutputMode::PowerShellMode {
+                    path_separator: ':',
+                },
+                true,
+            ),
+            (
+                "porcelain",
+                VariableOutputMode::PorcelainMode { end_marker: false },
+                false,
+            ),
+            ("json", VariableOutputMode::JsonMode, false),
+            ("pretty-json", VariableOutputMode::PrettyJsonMode, false),
+        ] {
+            let mut out: Vec<u8> = vec![];
+            let mut err: Vec<u8> = vec![];
+            apply_env_to(
+                &mut out, &mut err, &shadowenv, &mode, activation, false, true,
+            )
+            .unwrap();
+            let out = String::from_utf8(out).unwrap();
+            let err = String::from_utf8(err).unwrap();
+
+            assert!(out.contains("VAR_A"), "{}", name);
+            assert!(!out.contains("activated"), "{}", name);
+            assert!(!out.contains("ruby"), "{}", name);
+            assert!(!out.contains("careful"), "{}", name);
+            // only shells show the banner; the warning is shown whatever reads the output.
+            assert_eq!(err.contains("activated"), shell, "{}", name);
+            assert_eq!(err.contains("ruby:3.1.2"), shell, "{}", name);
+            assert!(err.contains("careful"), "{}", name);
+        }
+    }
+
+    #[test]
+    fn warnings_stay_out_of_posix_output() {
+        let source = build_source(
+            r#"
+                (warn "VAR_A is deprecated")
+                (env/set "VAR_A" "a")
+                (warn "so is this")
+            "#,
+        );
+        let shadowenv = activate(source, "".to_string(), HashMap::new());
+        assert_eq!(
+            shadowenv.warnings(),
+            &["VAR_A is deprecated".to_string(), "so is this".to_string()]
+        );
+
+        let mut out: Vec<u8> = vec![];
+        write_env(
+            &mut out,
+            &shadowenv,
+            &VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+            false,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(!out.contains("deprecated"));
+        assert!(out.lines().all(|line| line.starts_with("export ")));
+        assert!(out.contains("export VAR_A=a\n"));
+    }
+
+    fn golden_shadowenv() -> Shadowenv {
+        let env: HashMap<String, String> = vec![
+            ("PATH", "/usr/bin:/bin"),
+            ("OLD_VAR", "going away"),
+            ("KEPT", "unchanged"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let mut shadowenv = Shadowenv::new(env, undo::Data::new(), 0x1234);
+        shadowenv.set_root("/src/project");
+        shadowenv.set("PLAIN", Some("value"));
+        shadowenv.set(
+            "NEEDS_ESCAPING",
+            Some("it's a \"test\" with $HOME and\ttabs"),
+        );
+        shadowenv.set("OLD_VAR", None);
+        shadowenv.set_unexported("PROMPT_HELPER", Some("[$PWD]"));
+        shadowenv.prepend_to_pathlist("PATH", "/opt/my tools/bin");
+        shadowenv
+    }
+
+    fn golden_path(name: &str) -> PathBuf {
+        [
+            env!("CARGO_MANIFEST_DIR"),
+            "tests",
+            "fixtures",
+            "golden",
+            name,
+        ]
+        .iter()
+        .collect()
+    }
+
+    #[test]
+    fn test_posix_golden_output() {
+        let actual = testing::render(
+            &golden_shadowenv(),
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+        );
+        testing::assert_golden(&golden_path("posix.txt"), &actual);
+    }
+
+    #[test]
+    fn posix_escape_keeps_control_characters_on_one_line() {
+        assert_eq!(posix_escape("plain"), "plain");
+        assert_eq!(posix_escape("it's"), "'it'\\''s'");
+        assert_eq!(posix_escape("one\ntwo"), "$'one\\ntwo'");
+        assert_eq!(posix_escape("a\tb\rc"), "$'a\\tb\\rc'");
+        assert_eq!(posix_escape("it's\n\\n"), "$'it\\'s\\n\\\\n'");
+        assert_eq!(posix_escape("bell\x07"), "$'bell\\x07'");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn multiline_values_survive_eval() {
+        let values = vec![
+            "-----BEGIN KEY-----\nabc\n-----END KEY-----\n",
+            "tab\tseparated\tvalue",
+            "\n\nit's \"quoted\" $HOME `cmd` \\n\x01\x1b[0m\r\n",
+        ];
+        let mut shadowenv = Shadowenv::new(HashMap::new(), undo::Data::new(), 0);
+        for (i, value) in values.iter().enumerate() {
+            shadowenv.set(&format!("MULTILINE_{}", i), Some(value));
+        }
+        let script = testing::render(
+            &shadowenv,
+            VariableOutputMode::PosixMode {
+                ansi_c_quotes: true,
+            },
+        );
+        assert!(script
+            .lines()
+            .all(|l| l.starts_with("export ") || l.is_empty()));
+
+        // shells without ANSI-C quoting get plain quotes, which keep the values just as well.
+        for shell in &["bash", "zsh", "sh", "dash"] {
+            let script = testing::render(&shadowenv, shell_output_mode(shell).unwrap());
+            let output = std::process::Command::new(shell)
+                .arg("-c")
+                .arg("eval \"$1\"; printf '%s\\0' \"$MULTILINE_0\" \"$MULTILINE_1\" \"$MULTILINE_2\"")
+                .arg(shell)
+                .arg(&script)
+                .output();
+            // not every machine running the tests has every shell.
+            let output = match output {
+                Ok(output) => output,
+                Err(_) => continue,
+            };
+            assert!(output.status.success(), "{} failed", shell);
+            let printed: Vec<&str> = std::str::from_utf8(&output.stdout)
+                .unwrap()
+                .split_terminator('\0')
+                .collect();
+            assert_eq!(printed, values, "{} didn't reproduce the values", shell);
+        }
+    }
+
+    #[test]
+    fn test_fish_golden_output() {
+        let actual = testing::render(&golden_shadowenv(), VariableOutputMode::FishMode);
+        testing::assert_golden(&golden_path("fish.txt"), &actual);
+    }
+
+    #[test]
+    fn test_porcelain_golden_output() {
+        let actual = testing::render(
+            &golden_shadowenv(),
+            VariableOutputMode::PorcelainMode { end_marker: false },
+        );
+        testing::assert_golden(&golden_path("porcelain.txt"), &actual);
+    }
+
+    #[test]
+    fn porcelain_end_marker_is_the_last_record() {
+        let mode = VariableOutputMode::PorcelainMode { end_marker: true };
+        let mut actual = testing::render(&golden_shadowenv(), mode).into_bytes();
+        end_porcelain(&mut actual, &mode, &Ok(())).unwrap();
+        let actual = String::from_utf8(actual).unwrap();
+        let records: Vec<&str> = actual.split_terminator('\x1E').collect();
+        assert_eq!(records.iter().filter(|r| r.starts_with('\x00')).count(), 1);
+        assert_eq!(records.last(), Some(&"\x00\x1F\x1F"));
+
+        // everything before it is what's printed without the marker.
+        let without = testing::render(
+            &golden_shadowenv(),
+            VariableOutputMode::PorcelainMode { end_marker: false },
+        );
+        assert_eq!(actual, format!("{}\x00\x1F\x1F\x1E", without));
+
+        // a failed run ends with the marker too, after the record for the error.
+        let mut out = vec![];
+        let err: Result<(), Error> = Err(trust::NotTrusted {
+            not_trusted_dir_path: "/src/app".to_string(),
+        }
+        .into());
+        end_porcelain(&mut out, &mode, &err).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.starts_with("\x04\x1F/src/app\x1F"));
+        assert!(out.ends_with("\x1E\x00\x1F\x1F\x1E"));
+
+        // and without it asked for, nothing but the error is printed.
+        let mut out = vec![];
+        let mode = VariableOutputMode::PorcelainMode { end_marker: false };
+        end_porcelain(&mut out, &mode, &Ok(())).unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_json_output_separates_unexported() {
+        let actual = testing::render(&golden_shadowenv(), VariableOutputMode::JsonMode);
+        let json: serde_json::Value = serde_json::from_str(&actual).unwrap();
+        assert_eq!(json["unexported"]["PROMPT_HELPER"], "[$PWD]");
+        assert_eq!(json["exported"]["PLAIN"], "value");
+        assert_eq!(json["exported"]["OLD_VAR"], serde_json::Value::Null);
+        assert!(json["exported"].get("PROMPT_HELPER").is_none());
+    }
+
+    #[test]
+    fn test_powershell_golden_output() {
+        let mode = VariableOutputMode::PowerShellMode {
+            path_separator: ':',
+        };
+        let actual = testing::render(&golden_shadowenv(), mode);
+        testing::assert_golden(&golden_path("powershell.txt"), &actual);
+    }
+
+    #[test]
+    fn test_windows_powershell_golden_output() {
+        let mode = VariableOutputMode::PowerShellMode {
+            path_separator: ';',
+        };
+        let actual = testing::render(&golden_shadowenv(), mode);
+        testing::assert_golden(&golden_path("powershell-windows.txt"), &actual);
+    }
+
+    #[test]
+    fn aborted_programs_apply_nothing() {
+        let source = build_source(
+            r#"
+                (env/set "VAR_A" "a")
+                (env/prepend-to-pathlist "PATH" "/opt/bin")
+                (error "Please run `bin/setup` first")
+            "#,
+        );
+        let env: HashMap<String, String> = vec![("PATH".to_string(), "/bin".to_string())]
+            .into_iter()
+            .collect();
+
+        let err = load(Some(source), "".to_string(), env, FORCED).unwrap_err();
+        assert_eq!(
+            output::hook_error_json(&err)["error"]["message"],
+            "Please run `bin/setup` first"
+        );
+        assert_eq!(output::hook_error_json(&err)["error"]["kind"], "aborted");
+    }
+
+    #[test]
+    fn timings_are_recorded_when_requested() {
+        let source = build_source(r#"(env/set "VAR_A" "a")"#);
+        let mut timings = Some(Timings::default());
+        load_env_from_source(
+            Some(source),
+            "".to_string(),
+            HashMap::new(),
+            FORCED,
+            &mut timings,
+        )
+        .unwrap();
+
+        let timings = timings.unwrap();
+        assert!(timings.eval > Duration::from_nanos(0));
+        assert!(timings.hash > Duration::from_nanos(0));
+    }
+
+    #[test]
+    fn login_flag_reaches_the_program() {
+        let source = build_source(
+            r#"
+                (if (is-login-shell)
+                  (env/set "SHELL_KIND" "login")
+                  (env/set "SHELL_KIND" "interactive"))
+            "#,
+        );
+
+        let (shadowenv, _) = load(
+            Some(source.clone()),
+            "".to_string(),
+            HashMap::new(),
+            LoadOptions {
+                login: true,
+                ..FORCED
+            },
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(shadowenv.get("SHELL_KIND"), Some("login".to_string()));
+
+        let shadowenv = activate(source, "".to_string(), HashMap::new());
+        assert_eq!(shadowenv.get("SHELL_KIND"), Some("interactive".to_string()));
+    }
 }
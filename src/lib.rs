@@ -0,0 +1,18 @@
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod cli;
+pub mod diff;
+pub mod execcmd;
+pub mod features;
+pub mod hash;
+pub mod hook;
+pub mod init;
+pub mod lang;
+pub mod loader;
+pub mod output;
+pub mod shadowenv;
+pub mod strict;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trust;
+pub mod undo;
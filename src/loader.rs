@@ -1,41 +1,482 @@
-use crate::hash::Source;
+use crate::features;
+use crate::hash::{Source, SourceFile};
+use crate::trust;
 
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::env;
 use std::fs;
 use std::io::ErrorKind;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use failure::Error;
+use failure::{Error, Fail};
+use ketos::lexer::{Lexer, Token};
+use ketos::parser::Parser;
+use ketos::{Interpreter, Scope, Value};
+use regex::Regex;
 
 pub const DEFAULT_RELATIVE_COMPONENT: &str = ".shadowenv.d";
 
+#[derive(Fail, Debug)]
+#[fail(
+    display = "include cycle detected: `{}` ends up including itself",
+    path
+)]
+pub struct IncludeCycle {
+    pub path: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "`{}` can't be included: only a {} directory can be",
+    path, component
+)]
+pub struct NotIncludable {
+    pub path: String,
+    pub component: &'static str,
+}
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "this project requires shadowenv >= {} (this is {}): please upgrade shadowenv",
+    required, current
+)]
+pub struct VersionTooOld {
+    pub required: String,
+    pub current: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "this project pins shadowenv {} in {} (this is {}): please install a compatible shadowenv",
+    pinned, path, current
+)]
+pub struct VersionPinMismatch {
+    pub pinned: String,
+    pub path: String,
+    pub current: String,
+}
+
+/// The file in a `.shadowenv.d` pinning the shadowenv version it's written for.
+pub const VERSION_FILE: &str = ".version";
+
+/// How many directories the upward search for a `.shadowenv.d` will look at, starting with (and
+/// counting) the current one, unless SHADOWENV_MAX_SEARCH_DEPTH says otherwise.
+pub const DEFAULT_MAX_SEARCH_DEPTH: usize = 256;
+
+/// Bounds on the upward search for a `.shadowenv.d`, so that very deep trees (or slow network
+/// filesystems) don't make every prompt slow.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchLimits {
+    /// The maximum number of directories to look in.
+    pub max_depth: usize,
+    /// The last directory to look in: its ancestors are never searched.
+    pub ceiling: Option<PathBuf>,
+}
+
+impl Default for SearchLimits {
+    fn default() -> Self {
+        SearchLimits {
+            max_depth: DEFAULT_MAX_SEARCH_DEPTH,
+            ceiling: None,
+        }
+    }
+}
+
+impl SearchLimits {
+    /// Read the limits from SHADOWENV_MAX_SEARCH_DEPTH and SHADOWENV_ROOT.
+    pub fn from_env() -> Self {
+        let max_depth = env::var("SHADOWENV_MAX_SEARCH_DEPTH")
+            .ok()
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_SEARCH_DEPTH);
+        let ceiling = env::var_os("SHADOWENV_ROOT")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+        SearchLimits { max_depth, ceiling }
+    }
+}
+
 /// Search upwards the filesystem branch starting with `at` and then its ancestors looking
-/// for a file or directory named `relative_component`.
+/// for a file or directory named `relative_component`, within the limits set in the environment.
 pub fn find_root(at: &PathBuf, relative_component: &str) -> Result<Option<PathBuf>, Error> {
-    for curr in at.ancestors() {
-        let dirpath = curr.join(relative_component);
+    find_root_within(at, relative_component, &SearchLimits::from_env())
+}
+
+/// Like `find_root`, but with explicit limits.
+pub fn find_root_within(
+    at: &Path,
+    relative_component: &str,
+    limits: &SearchLimits,
+) -> Result<Option<PathBuf>, Error> {
+    search_ancestors(
+        at,
+        relative_component,
+        limits,
+        |dirpath| match fs::metadata(dirpath) {
+            Ok(metadata) => Ok(metadata.is_dir()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        },
+    )
+}
 
-        match fs::read_dir(&dirpath) {
-            Ok(_) => return Ok(Some(fs::canonicalize(dirpath)?)),
-            Err(ref e) if e.kind() == ErrorKind::NotFound => (),
-            Err(e) => return Err(e.into()),
+/// Each ancestor is probed exactly once, so a search does at most `max_depth` stats.
+fn search_ancestors<F>(
+    at: &Path,
+    relative_component: &str,
+    limits: &SearchLimits,
+    mut probe: F,
+) -> Result<Option<PathBuf>, Error>
+where
+    F: FnMut(&Path) -> Result<bool, Error>,
+{
+    for curr in at.ancestors().take(limits.max_depth) {
+        let dirpath = curr.join(relative_component);
+        if probe(&dirpath)? {
+            return Ok(Some(fs::canonicalize(dirpath)?));
+        }
+        if limits.ceiling.as_deref() == Some(curr) {
+            break;
         }
     }
     Ok(None)
 }
 
 /// Load all .lisp files in the directory pointed by `dirpath` storing their names and contents as
-/// `SourceFiles` inside a `Source` struct.
+/// `SourceFiles` inside a `Source` struct, along with any other `.shadowenv.d` directories they
+/// `include`.
 ///
-/// Note that this function assumes that the dirpath is trusted.
+/// Note that this function assumes that the dirpath is trusted. Included directories are not
+/// assumed to be, and must be trusted in their own right.
 pub fn load(dirpath: PathBuf) -> Result<Option<Source>, Error> {
+    let source = load_unresolved(&dirpath)?;
+    resolve(source, dirpath)
+}
+
+/// Finish loading a source from `load_unresolved`, adding what it includes and every data file
+/// read by it or by them. This is for a `.shadowenv.d` whose trust was checked on the source as
+/// loaded, so it needn't be read again.
+pub fn resolve(mut source: Source, dirpath: PathBuf) -> Result<Option<Source>, Error> {
+    if source.files.is_empty() {
+        return Ok(None);
+    }
+
+    let mut includes = BTreeMap::new();
+    let mut stack = vec![fs::canonicalize(&dirpath)?];
+    resolve_includes(&source, &mut stack, &mut includes)?;
+    source.includes = includes;
+    source.data_files = load_data_files(&source);
+
+    Ok(Some(source))
+}
+
+/// Resolve the path given to `include` to the canonical path of the `.shadowenv.d` it refers to.
+/// Relative paths are relative to `base`, the directory containing the including `.shadowenv.d`.
+pub fn resolve_include_path(base: &Path, path: &str) -> Result<PathBuf, Error> {
+    let expanded = PathBuf::from(shellexpand::tilde(path).to_string());
+    Ok(fs::canonicalize(base.join(expanded))?)
+}
+
+fn resolve_includes(
+    source: &Source,
+    stack: &mut Vec<PathBuf>,
+    includes: &mut BTreeMap<String, Source>,
+) -> Result<(), Error> {
+    for path in include_paths(source) {
+        let dirpath = resolve_include_path(Path::new(&source.dir), &path)?;
+        if stack.contains(&dirpath) {
+            return Err(IncludeCycle {
+                path: dirpath.to_string_lossy().to_string(),
+            }
+            .into());
+        }
+        let key = dirpath.to_string_lossy().to_string();
+        if includes.contains_key(&key) {
+            continue;
+        }
+        // anything else would be trusted along with the .shadowenv.d above it, whose signature
+        // doesn't cover it.
+        if dirpath.file_name() != Some(DEFAULT_RELATIVE_COMPONENT.as_ref()) {
+            return Err(NotIncludable {
+                path: key,
+                component: DEFAULT_RELATIVE_COMPONENT,
+            }
+            .into());
+        }
+        if !trust::is_root_trusted(&dirpath)? {
+            return Err(trust::NotTrusted {
+                not_trusted_dir_path: key,
+            }
+            .into());
+        }
+
+        let included = load_files(&dirpath)?;
+        stack.push(dirpath);
+        resolve_includes(&included, stack, includes)?;
+        stack.pop();
+        includes.insert(key, included);
+    }
+    Ok(())
+}
+
+/// Find the paths passed to `include` in a source. Since the included files have to be hashed
+/// before anything is evaluated, only string literals can be included.
+fn include_paths(source: &Source) -> Vec<String> {
+    literal_calls(source.files.iter(), &["include"])
+}
+
+/// The string literals passed as the first argument to any of `functions`, wherever they're called
+/// in `files`. The files are parsed rather than searched, so comments and strings can neither hide
+/// a call nor fake one. Files which don't parse have none: running them reports the error.
+fn literal_calls<'a>(
+    files: impl Iterator<Item = &'a SourceFile>,
+    functions: &[&str],
+) -> Vec<String> {
+    let interp = Interpreter::new();
+    let mut literals = vec![];
+    for file in files {
+        let contents = undocument(&file.contents);
+        let lexer = Lexer::new(&contents, 0);
+        if let Ok(exprs) = Parser::new(interp.context(), lexer).parse_exprs() {
+            for expr in &exprs {
+                find_literal_calls(interp.scope(), expr, functions, &mut literals);
+            }
+        }
+    }
+    literals
+}
+
+fn find_literal_calls(
+    scope: &Scope,
+    value: &Value,
+    functions: &[&str],
+    literals: &mut Vec<String>,
+) {
+    let items = match value {
+        Value::List(items) => items,
+        _ => return,
+    };
+    if let (Value::Name(name), Some(Value::String(literal))) = (&items[0], items.get(1)) {
+        if scope.with_name(*name, |name| functions.contains(&name)) {
+            literals.push(literal.to_string());
+        }
+    }
+    for item in items.iter() {
+        find_literal_calls(scope, item, functions, literals);
+    }
+}
+
+/// Ketos reads comments starting with `;;` as documentation, which may only precede a definition,
+/// so a `;;` comment anywhere else fails to parse. Turn them all into plain comments, by blanking
+/// out all but the first semicolon of each line. Nothing moves, so error locations still hold.
+pub fn undocument(contents: &str) -> String {
+    let mut bytes = contents.as_bytes().to_vec();
+    let mut lexer = Lexer::new(contents, 0);
+    loop {
+        match lexer.next_token() {
+            Ok((span, Token::DocComment(_))) => {
+                // whether we're in the semicolons at the start of a line, having seen the first.
+                let mut leading = true;
+                let mut seen = false;
+                for byte in &mut bytes[span.lo as usize..span.hi as usize] {
+                    match *byte {
+                        b'\n' => {
+                            leading = true;
+                            seen = false;
+                        }
+                        b';' if leading && seen => *byte = b' ',
+                        b';' if leading => seen = true,
+                        b' ' | b'\t' | b'\r' if leading && !seen => (),
+                        _ => leading = false,
+                    }
+                }
+            }
+            Ok((_, Token::End)) | Err(_) => break,
+            Ok(_) => (),
+        }
+    }
+    // only ASCII semicolons were replaced, with ASCII spaces.
+    String::from_utf8(bytes).unwrap()
+}
+
+/// The byte offsets of the calls to `function` in `contents`, each with its first argument if
+/// that's a string literal. Like `literal_calls`, this goes by the tokens rather than the text, so
+/// comments, strings and quoted lists hold no calls, and a call may span lines; unlike it, a file
+/// needn't parse to be searched, as far as it lexes.
+pub fn call_sites(
+    interp: &Interpreter,
+    contents: &str,
+    function: &str,
+) -> Vec<(usize, Option<String>)> {
+    let mut lexer = Lexer::new(contents, 0);
+    let mut tokens = vec![];
+    loop {
+        match lexer.next_token() {
+            Ok((_, Token::End)) | Err(_) => break,
+            Ok((_, Token::DocComment(_))) => (),
+            Ok(token) => tokens.push(token),
+        }
+    }
+
+    // whether each of the lists we're in is quoted, and so data rather than code.
+    let mut quoted = vec![false];
+    let mut sites = vec![];
+    for (i, (span, token)) in tokens.iter().enumerate() {
+        match token {
+            Token::LeftParen => {
+                let inner = match i.checked_sub(1).map(|prev| tokens[prev].1) {
+                    Some(Token::Quote) | Some(Token::BackQuote) => true,
+                    Some(Token::Comma) | Some(Token::CommaAt) => false,
+                    _ => *quoted.last().unwrap(),
+                };
+                quoted.push(inner);
+                if inner || tokens.get(i + 1).map(|t| t.1) != Some(Token::Name(function)) {
+                    continue;
+                }
+                let literal = match tokens.get(i + 2) {
+                    Some((_, Token::String(text))) => string_literal(interp, text),
+                    _ => None,
+                };
+                sites.push((span.lo as usize, literal));
+            }
+            Token::RightParen if quoted.len() > 1 => {
+                quoted.pop();
+            }
+            _ => (),
+        }
+    }
+    sites
+}
+
+/// The value of a string literal token, escapes and all.
+fn string_literal(interp: &Interpreter, text: &str) -> Option<String> {
+    match Parser::new(interp.context(), Lexer::new(text, 0)).parse_single_expr() {
+        Ok(Value::String(s)) => Some(s.to_string()),
+        _ => None,
+    }
+}
+
+/// Load the files named by `read-json` and `read-toml` calls in the source or anything it
+/// includes, so that they contribute to the hash. As with `include`, only string literals can be
+/// found this way, and only in files that parse. Paths are relative to the project directory, and files which don't exist or
+/// lie outside of it are left out, for the builtins to complain about if they're reached.
+fn load_data_files(source: &Source) -> BTreeMap<String, String> {
+    let project_dir = match fs::canonicalize(&source.dir) {
+        Ok(dir) => dir,
+        Err(_) => return BTreeMap::new(),
+    };
+    let files = source
+        .files
+        .iter()
+        .chain(source.includes.values().flat_map(|s| s.files.iter()));
+    literal_calls(files, &["read-json", "read-toml"])
+        .into_iter()
+        .filter_map(|path| {
+            let resolved = fs::canonicalize(project_dir.join(&path)).ok()?;
+            if !resolved.starts_with(&project_dir) {
+                return None;
+            }
+            let contents = fs::read_to_string(resolved).ok()?;
+            Some((path, contents))
+        })
+        .collect()
+}
+
+/// The newest shadowenv version required by a `;; @shadowenv-version` directive in the source or
+/// anything it includes, if any.
+pub fn required_version(source: &Source) -> Option<String> {
+    let re = Regex::new(r"(?m)^\s*;+\s*@shadowenv-version\s+(\S+)\s*$").unwrap();
+    source
+        .files
+        .iter()
+        .chain(source.includes.values().flat_map(|s| s.files.iter()))
+        .flat_map(|file| {
+            re.captures_iter(&file.contents)
+                .map(|cap| cap[1].to_string())
+                .collect::<Vec<String>>()
+        })
+        .max_by(|a, b| features::compare_versions(a, b))
+}
+
+/// Refuse to run a source declaring a `;; @shadowenv-version` newer than `current`, rather than
+/// risk running it with semantics it doesn't expect.
+pub fn check_required_version(source: &Source, current: &str) -> Result<(), VersionTooOld> {
+    match required_version(source) {
+        Some(required) if features::compare_versions(current, &required) == Ordering::Less => {
+            Err(VersionTooOld {
+                required,
+                current: current.to_string(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Refuse to load a `.shadowenv.d` whose `.version` file pins a shadowenv version `current` isn't
+/// compatible with. Unlike `;; @shadowenv-version`, this is checked before any of the programs are
+/// read, and newer versions are turned away too: `current` has to be at least the pinned version,
+/// and agree with it up to and including its first non-zero component, so a pin of `2.1` accepts
+/// `2.3.0` but not `3.0.0`, and a pin of `0.4` accepts `0.4.2` but not `0.5.0`.
+pub fn check_pinned_version(dirpath: &Path, current: &str) -> Result<(), Error> {
+    let path = dirpath.join(VERSION_FILE);
+    let pinned = match fs::read_to_string(&path) {
+        Ok(contents) => contents.trim().to_string(),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    if pinned.is_empty() || is_compatible_version(current, &pinned) {
+        return Ok(());
+    }
+    Err(VersionPinMismatch {
+        pinned,
+        path: path.to_string_lossy().to_string(),
+        current: current.to_string(),
+    }
+    .into())
+}
+
+fn is_compatible_version(current: &str, pinned: &str) -> bool {
+    if features::compare_versions(current, pinned) == Ordering::Less {
+        return false;
+    }
+    let current: Vec<&str> = current.split('.').collect();
+    for (i, component) in pinned.split('.').enumerate() {
+        if features::compare_versions(component, current.get(i).copied().unwrap_or("0"))
+            != Ordering::Equal
+        {
+            return false;
+        }
+        if component.parse::<u64>() != Ok(0) {
+            break;
+        }
+    }
+    true
+}
+
+/// The programs in a `.shadowenv.d` and the data files they read, leaving aside whatever they
+/// include, which is trusted on its own: this is what `shadowenv trust` is scoped to.
+pub fn load_unresolved(dirpath: &Path) -> Result<Source, Error> {
+    let mut source = load_files(dirpath)?;
+    source.data_files = load_data_files(&source);
+    Ok(source)
+}
+
+/// The hash of what `load_unresolved` loads.
+pub fn content_hash(dirpath: &Path) -> Result<u64, Error> {
+    load_unresolved(dirpath)?.hash()
+}
+
+/// Only files that will be evaluated are loaded, and so only they contribute to the hash: other
+/// files (READMEs, `.ruby-version`, editor droppings) can come and go without re-activating.
+fn load_files(dirpath: &Path) -> Result<Source, Error> {
     let mut source = Source::new(dirpath.parent().unwrap().to_string_lossy().to_string());
 
     for entry in fs::read_dir(dirpath)?.flatten() {
         let path = entry.path();
         if path.is_file() {
-            // TODO: there HAS to be a better way to do this.
             let basename = path.file_name().unwrap().to_string_lossy().to_string();
-            if !basename.ends_with(".lisp") {
+            if !is_program_file(&basename) {
                 continue;
             }
             let contents = fs::read_to_string(&path)?;
@@ -43,16 +484,20 @@ pub fn load(dirpath: PathBuf) -> Result<Option<Source>, Error> {
         }
     }
 
-    if source.files.is_empty() {
-        return Ok(None);
-    }
-    Ok(Some(source))
+    Ok(source)
+}
+
+/// Hidden files are never programs, even if named like one: these are things like emacs lockfiles
+/// (`.#500_app.lisp`) and macOS resource forks (`._500_app.lisp`), as well as our own trust and
+/// error files.
+fn is_program_file(basename: &str) -> bool {
+    basename.ends_with(".lisp") && !basename.starts_with('.')
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::hash::SourceFile;
+    use tempfile::tempdir;
 
     #[test]
     fn test_load() {
@@ -82,4 +527,334 @@ mod tests {
 
         assert_eq!(files, expected)
     }
+
+    #[test]
+    fn test_load_rejects_include_cycles() {
+        let temp_dir = tempdir().unwrap();
+        let dirpath = temp_dir.path().join(".shadowenv.d");
+        fs::create_dir(&dirpath).unwrap();
+        fs::write(dirpath.join("a.lisp"), "(include \".shadowenv.d\")").unwrap();
+
+        let err = load(dirpath.clone()).unwrap_err();
+        assert!(err.downcast_ref::<IncludeCycle>().is_some());
+        assert_eq!(
+            format!(
+                "include cycle detected: `{}` ends up including itself",
+                fs::canonicalize(dirpath).unwrap().display()
+            ),
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_untrusted_includes() {
+        let temp_dir = tempdir().unwrap();
+        let dirpath = temp_dir.path().join(".shadowenv.d");
+        let shared = temp_dir.path().join("shared").join(".shadowenv.d");
+        fs::create_dir(&dirpath).unwrap();
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(shared.join("b.lisp"), "(env/set \"B\" \"1\")").unwrap();
+        fs::write(
+            dirpath.join("a.lisp"),
+            ";; (include \"nowhere/.shadowenv.d\")\n(include \"shared/.shadowenv.d\")",
+        )
+        .unwrap();
+
+        let err = load(dirpath).unwrap_err();
+        assert!(err.downcast_ref::<trust::NotTrusted>().is_some());
+    }
+
+    #[test]
+    fn test_load_rejects_includes_of_other_directories() {
+        let temp_dir = tempdir().unwrap();
+        let dirpath = temp_dir.path().join(".shadowenv.d");
+        let lib = dirpath.join("lib");
+        fs::create_dir_all(&lib).unwrap();
+        fs::write(lib.join("b.lisp"), "(env/set \"B\" \"1\")").unwrap();
+        fs::write(dirpath.join("a.lisp"), "(include \".shadowenv.d/lib\")").unwrap();
+
+        let err = load(dirpath).unwrap_err();
+        assert!(err.downcast_ref::<NotIncludable>().is_some());
+        assert_eq!(
+            format!(
+                "`{}` can't be included: only a .shadowenv.d directory can be",
+                fs::canonicalize(lib).unwrap().display()
+            ),
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_include_paths() {
+        let mut source = Source::new("/src/project".to_string());
+        source.add_file(
+            "a.lisp".to_string(),
+            r#"(env/set "SEP" ";") (include "after/.shadowenv.d")
+; (include "commented/.shadowenv.d")
+;; (include "documented/.shadowenv.d")
+(env/set "TEXT" "(include \"in-a-string/.shadowenv.d\")")
+(when (is-ci)
+  (include
+    "nested/.shadowenv.d"))
+'(include "quoted/.shadowenv.d")
+"#
+            .to_string(),
+        );
+        assert_eq!(
+            include_paths(&source),
+            vec!["after/.shadowenv.d", "nested/.shadowenv.d"]
+        );
+
+        // files which don't parse are left for evaluation to complain about.
+        source.add_file("b.lisp".to_string(), r#"(include "x""#.to_string());
+        assert_eq!(include_paths(&source).len(), 2);
+    }
+
+    #[test]
+    fn test_call_sites() {
+        let interp = Interpreter::new();
+        let contents = r#"; (env/sett "commented")
+;; (env/sett "documented")
+(env/set "TEXT" "(env/sett \"in-a-string\"")
+'(env/sett "quoted")
+(env/sett-more "longer")
+(when (is-ci)
+  (env/sett
+    "spread\tout"))
+`(a ,(env/sett b))
+"#;
+        let sites = call_sites(&interp, contents, "env/sett");
+        let offset = |text| contents.find(text).unwrap();
+        assert_eq!(
+            sites,
+            vec![
+                (offset("(env/sett\n"), Some("spread\tout".to_string())),
+                (offset("(env/sett b"), None),
+            ]
+        );
+
+        // as much as lexes is searched.
+        assert_eq!(
+            call_sites(&interp, "(env/sett \"x\")\n(when", "env/sett"),
+            vec![(0, Some("x".to_string()))]
+        );
+    }
+
+    #[test]
+    fn test_load_ignores_stray_files() {
+        let temp_dir = tempdir().unwrap();
+        let dirpath = temp_dir.path().join(".shadowenv.d");
+        fs::create_dir(&dirpath).unwrap();
+        fs::write(dirpath.join("500_app.lisp"), "(provide \"app\")").unwrap();
+        let clean = load(dirpath.clone()).unwrap().unwrap();
+
+        fs::write(dirpath.join("README.md"), "# not lisp").unwrap();
+        fs::write(dirpath.join(".ruby-version"), "3.1.2").unwrap();
+        fs::write(dirpath.join(".DS_Store"), [0u8, 159, 146, 150]).unwrap();
+        fs::write(dirpath.join("._500_app.lisp"), [0u8, 159, 146, 150]).unwrap();
+        fs::write(dirpath.join(".500_app.lisp.swp"), "swap").unwrap();
+        fs::write(dirpath.join("500_app.lisp~"), "(provide \"old\")").unwrap();
+        fs::create_dir(dirpath.join("nested.lisp")).unwrap();
+        let cluttered = load(dirpath).unwrap().unwrap();
+
+        let names: Vec<String> = cluttered.files.iter().map(|f| f.name.clone()).collect();
+        assert_eq!(names, vec!["500_app.lisp".to_string()]);
+        assert_eq!(clean.hash().unwrap(), cluttered.hash().unwrap());
+    }
+
+    #[test]
+    fn test_load_without_programs() {
+        let temp_dir = tempdir().unwrap();
+        let dirpath = temp_dir.path().join(".shadowenv.d");
+        fs::create_dir(&dirpath).unwrap();
+        fs::write(dirpath.join("README.md"), "# not lisp").unwrap();
+
+        assert!(load(dirpath).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_load_reads_data_files() {
+        let temp_dir = tempdir().unwrap();
+        let project_path = fs::canonicalize(temp_dir.path()).unwrap().join("project");
+        let dirpath = project_path.join(".shadowenv.d");
+        fs::create_dir_all(&dirpath).unwrap();
+        fs::write(
+            dirpath.join("a.lisp"),
+            r#"(env/set "NODE" (read-json "package.json" "engines.node"))
+(env/set "SEP" ";") (env/set "RUBY" (read-toml "after.toml" "tools.ruby"))
+;; (read-toml "commented.toml" "x")
+(env/set "TEXT" "(read-json \"in-a-string.json\" \"x\")")
+(read-toml "missing.toml" "tools.ruby")
+(read-json "../outside.json" "x")"#,
+        )
+        .unwrap();
+        fs::write(project_path.join("package.json"), "{}").unwrap();
+        fs::write(project_path.join("after.toml"), "").unwrap();
+        fs::write(project_path.join("commented.toml"), "").unwrap();
+        fs::write(project_path.join("in-a-string.json"), "{}").unwrap();
+        fs::write(temp_dir.path().join("outside.json"), "{}").unwrap();
+
+        let source = load(dirpath).unwrap().unwrap();
+        assert_eq!(
+            source.data_files.keys().collect::<Vec<_>>(),
+            vec!["after.toml", "package.json"]
+        );
+        assert_eq!(source.data_files["package.json"], "{}");
+    }
+
+    fn source_with(contents: &[&str]) -> Source {
+        let mut source = Source::new("/src/project".to_string());
+        for (i, contents) in contents.iter().enumerate() {
+            source.add_file(format!("{}.lisp", i), contents.to_string());
+        }
+        source
+    }
+
+    #[test]
+    fn test_required_version() {
+        assert_eq!(required_version(&source_with(&["(provide \"app\")"])), None);
+        let source = source_with(&[
+            ";; @shadowenv-version 2\n(provide \"app\")",
+            "  ;; @shadowenv-version 2.1.0  \n",
+            "(env/set \"A\" \";; @shadowenv-version 9\")",
+        ]);
+        assert_eq!(required_version(&source), Some("2.1.0".to_string()));
+    }
+
+    #[test]
+    fn test_check_required_version() {
+        let source = source_with(&[";; @shadowenv-version 2.1\n(provide \"app\")"]);
+        assert!(check_required_version(&source, "2.1.0").is_ok());
+        assert!(check_required_version(&source, "3.0.0").is_ok());
+        assert!(check_required_version(&source_with(&["()"]), "1.0.0").is_ok());
+
+        let err = check_required_version(&source, "2.0.7").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "this project requires shadowenv >= 2.1 (this is 2.0.7): please upgrade shadowenv"
+        );
+    }
+
+    #[test]
+    fn test_check_pinned_version() {
+        let dir = tempdir().unwrap();
+        assert!(check_pinned_version(dir.path(), "2.1.0").is_ok());
+
+        let pin = |version: &str| fs::write(dir.path().join(VERSION_FILE), version).unwrap();
+        pin("2.1\n");
+        assert!(check_pinned_version(dir.path(), "2.1.0").is_ok());
+        assert!(check_pinned_version(dir.path(), "2.3.4").is_ok());
+        assert!(check_pinned_version(dir.path(), "2.0.7").is_err());
+        let err = check_pinned_version(dir.path(), "3.0.0").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "this project pins shadowenv 2.1 in {} (this is 3.0.0): please install a compatible shadowenv",
+                dir.path().join(VERSION_FILE).display()
+            )
+        );
+
+        pin("0.4");
+        assert!(check_pinned_version(dir.path(), "0.4.2").is_ok());
+        assert!(check_pinned_version(dir.path(), "0.5.0").is_err());
+
+        pin("  \n");
+        assert!(check_pinned_version(dir.path(), "1.0.0").is_ok());
+    }
+
+    #[test]
+    fn test_load_ignores_version_file() {
+        let dir = tempdir().unwrap();
+        let dirpath = dir.path().join(".shadowenv.d");
+        fs::create_dir(&dirpath).unwrap();
+        fs::write(dirpath.join("a.lisp"), "(provide \"app\")").unwrap();
+        let without_pin = load(dirpath.clone()).unwrap().unwrap().hash().unwrap();
+
+        fs::write(dirpath.join(VERSION_FILE), "2.1").unwrap();
+        let source = load(dirpath).unwrap().unwrap();
+        assert_eq!(source.files.len(), 1);
+        assert_eq!(source.hash().unwrap(), without_pin);
+    }
+
+    fn deep_tree(root: &Path, depth: usize) -> PathBuf {
+        let mut deepest = root.to_path_buf();
+        for _ in 0..depth {
+            deepest.push("d");
+        }
+        fs::create_dir_all(&deepest).unwrap();
+        deepest
+    }
+
+    #[test]
+    fn test_find_root_in_deep_tree() {
+        let temp_dir = tempdir().unwrap();
+        let root = fs::canonicalize(temp_dir.path()).unwrap();
+        fs::create_dir(root.join(".shadowenv.d")).unwrap();
+        let deepest = deep_tree(&root, 300);
+
+        let limits = SearchLimits {
+            max_depth: 1000,
+            ceiling: None,
+        };
+        let mut probes = 0;
+        let found = search_ancestors(&deepest, ".shadowenv.d", &limits, |dirpath| {
+            probes += 1;
+            Ok(dirpath.exists())
+        })
+        .unwrap();
+        assert_eq!(found, Some(root.join(".shadowenv.d")));
+        assert_eq!(probes, 301);
+
+        assert_eq!(
+            find_root_within(&deepest, ".shadowenv.d", &limits).unwrap(),
+            Some(root.join(".shadowenv.d"))
+        );
+    }
+
+    #[test]
+    fn test_find_root_respects_max_depth() {
+        let temp_dir = tempdir().unwrap();
+        let root = fs::canonicalize(temp_dir.path()).unwrap();
+        fs::create_dir(root.join(".shadowenv.d")).unwrap();
+        let deepest = deep_tree(&root, 300);
+
+        let limits = SearchLimits {
+            max_depth: 100,
+            ceiling: None,
+        };
+        let mut probes = 0;
+        let found = search_ancestors(&deepest, ".shadowenv.d", &limits, |dirpath| {
+            probes += 1;
+            Ok(dirpath.exists())
+        })
+        .unwrap();
+        assert_eq!(found, None);
+        assert_eq!(probes, 100);
+    }
+
+    #[test]
+    fn test_find_root_stops_at_ceiling() {
+        let temp_dir = tempdir().unwrap();
+        let root = fs::canonicalize(temp_dir.path()).unwrap();
+        fs::create_dir(root.join(".shadowenv.d")).unwrap();
+        let deepest = deep_tree(&root, 10);
+
+        let limits = SearchLimits {
+            max_depth: 1000,
+            ceiling: Some(root.join("d")),
+        };
+        assert_eq!(
+            find_root_within(&deepest, ".shadowenv.d", &limits).unwrap(),
+            None
+        );
+
+        let limits = SearchLimits {
+            max_depth: 1000,
+            ceiling: Some(root.clone()),
+        };
+        assert_eq!(
+            find_root_within(&deepest, ".shadowenv.d", &limits).unwrap(),
+            Some(root.join(".shadowenv.d"))
+        );
+    }
 }
@@ -1,24 +1,12 @@
-mod cli;
-mod diff;
-mod execcmd;
-mod features;
-mod hash;
-mod hook;
-mod init;
-mod lang;
-mod loader;
-mod output;
-mod shadowenv;
-mod trust;
-mod undo;
-
-use crate::shadowenv::Shadowenv;
+use clap::ArgMatches;
 use failure::format_err;
+use shadowenv::shadowenv::Shadowenv;
+use shadowenv::{cli, diff, execcmd, hook, init, output, trust};
 use std::env;
 use std::path::PathBuf;
 use std::process;
 
-use crate::hook::VariableOutputMode;
+use shadowenv::hook::VariableOutputMode;
 
 fn main() {
     let current_dir = match env::current_dir() {
@@ -33,19 +21,64 @@ fn main() {
             let shellpid = determine_shellpid_or_crash(matches.value_of("shellpid"));
             let force = matches.is_present("force");
 
-            let mode = match true {
-                true if matches.is_present("porcelain") => VariableOutputMode::PorcelainMode,
-                true if matches.is_present("fish") => VariableOutputMode::FishMode,
-                true if matches.is_present("json") => VariableOutputMode::JsonMode,
-                true if matches.is_present("pretty-json") => VariableOutputMode::PrettyJsonMode,
-                _ => VariableOutputMode::PosixMode,
+            let mode = output_mode(matches);
+            let json_errors = match mode {
+                VariableOutputMode::JsonMode => Some(false),
+                VariableOutputMode::PrettyJsonMode => Some(true),
+                _ => None,
+            };
+            let options = hook::HookOptions {
+                login: matches.is_present("login"),
+                delta: matches.is_present("delta"),
+                export_data: !matches.is_present("no-export-data"),
+                shellpid: Some(shellpid),
             };
-            if let Err(err) = hook::run(current_dir, data, mode, force) {
-                process::exit(output::handle_hook_error(
-                    err,
-                    shellpid,
-                    matches.is_present("silent"),
-                ));
+            if !options.export_data
+                && !matches!(
+                    mode,
+                    VariableOutputMode::PosixMode { .. } | VariableOutputMode::FishMode
+                )
+            {
+                clap::Error::with_description(
+                    "--no-export-data only applies to posix and fish output",
+                    clap::ErrorKind::ArgumentConflict,
+                )
+                .exit();
+            }
+            match hook::run_reporting_change(current_dir, data, mode, force, options) {
+                Ok(false) if matches.is_present("exit-code-on-no-change") => {
+                    process::exit(hook::NO_CHANGE_EXIT_CODE);
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    if let Some(pretty) = json_errors {
+                        println!("{}", output::format_hook_error_json(&err, pretty));
+                    }
+                    process::exit(output::handle_hook_error(
+                        err,
+                        shellpid,
+                        matches.is_present("silent"),
+                    ));
+                }
+            }
+        }
+        ("unload", Some(matches)) => {
+            let data = Shadowenv::load_shadowenv_data_or_legacy_fallback(None);
+            if let Err(err) = hook::unload(data, output_mode(matches)) {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+        ("hook-multi", Some(matches)) => {
+            let data = Shadowenv::load_shadowenv_data_or_legacy_fallback(None);
+            let dirs = matches
+                .values_of("dirs")
+                .unwrap()
+                .map(PathBuf::from)
+                .collect();
+            if let Err(err) = hook::run_multi(dirs, data, matches.is_present("pretty-json")) {
+                eprintln!("{}", err);
+                process::exit(1);
             }
         }
         ("diff", Some(matches)) => {
@@ -79,6 +112,12 @@ fn main() {
                 process::exit(1);
             }
         }
+        ("capabilities", Some(_)) => {
+            println!(
+                "{}",
+                serde_json::to_string(&output::capabilities()).unwrap()
+            );
+        }
         ("init", Some(matches)) => {
             let shellname = matches.subcommand_name().unwrap();
             process::exit(init::run(shellname));
@@ -89,6 +128,42 @@ fn main() {
     }
 }
 
+fn output_mode(matches: &ArgMatches) -> VariableOutputMode {
+    match true {
+        true if matches.is_present("porcelain") => VariableOutputMode::PorcelainMode {
+            end_marker: matches.is_present("end-marker"),
+        },
+        true if matches.is_present("fish") => VariableOutputMode::FishMode,
+        true if matches.is_present("powershell") => VariableOutputMode::PowerShellMode {
+            path_separator: match matches.value_of("path-separator") {
+                Some(sep) => sep.chars().next().unwrap(),
+                None => hook::default_powershell_path_separator(),
+            },
+        },
+        true if matches.value_of("shell") == Some("auto") => {
+            hook::detect_output_mode(determine_shellpid_or_crash(matches.value_of("shellpid")))
+        }
+        true if matches.is_present("shell") => {
+            let shell = matches.value_of("shell").unwrap();
+            hook::shell_output_mode(shell).unwrap_or_else(|| {
+                clap::Error::with_description(
+                    &format!(
+                        "'{}' isn't a shell shadowenv knows (fish, pwsh, bash, zsh...); try 'auto'",
+                        shell
+                    ),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit()
+            })
+        }
+        true if matches.is_present("json") => VariableOutputMode::JsonMode,
+        true if matches.is_present("pretty-json") => VariableOutputMode::PrettyJsonMode,
+        _ => VariableOutputMode::PosixMode {
+            ansi_c_quotes: true,
+        },
+    }
+}
+
 fn determine_shellpid_or_crash(arg: Option<&str>) -> u32 {
     match arg {
         Some(arg) => arg
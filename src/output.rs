@@ -1,13 +1,19 @@
+use crate::cli;
 use crate::features::Feature;
+use crate::lang::{Abort, AbortReason, Location, ShadowLang, ShadowlispError};
 use crate::loader;
+use crate::strict;
 use crate::trust;
+use crate::undo;
 
 use atty::{is, Stream};
 use failure::{format_err, Error};
 use regex::Regex;
+use serde_derive::Serialize;
 use std::collections::HashSet;
 use std::env;
 use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::path::PathBuf;
 use std::time::{Duration, SystemTime};
 
@@ -20,10 +26,186 @@ const SHADOWENV: &'static str = concat!(
 
 const COOLDOWN_SECONDS: u64 = 5;
 
+// Past this many features, the activation message starts wrapping on most terminals.
+const DEFAULT_FEATURE_DISPLAY_LIMIT: usize = 5;
+
 fn cooldown() -> Duration {
     Duration::new(COOLDOWN_SECONDS, 0)
 }
 
+/// The kinds of failure reported to consumers of the JSON output modes.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    NotTrusted,
+    Shadowlisp,
+    Aborted,
+    FeatureConflict,
+    FeatureRequired,
+    EnvRequired,
+    UnknownFunction,
+    IncludeCycle,
+    Other,
+}
+
+impl ErrorKind {
+    pub fn of(err: &Error) -> ErrorKind {
+        if err.downcast_ref::<trust::NotTrusted>().is_some() {
+            ErrorKind::NotTrusted
+        } else if err.downcast_ref::<ShadowlispError>().is_some() {
+            ErrorKind::Shadowlisp
+        } else if let Some(abort) = err.downcast_ref::<Abort>() {
+            match abort.reason {
+                AbortReason::Error { .. } => ErrorKind::Aborted,
+                AbortReason::FeatureConflict { .. } => ErrorKind::FeatureConflict,
+                AbortReason::FeatureRequired { .. } => ErrorKind::FeatureRequired,
+                AbortReason::EnvRequired { .. } => ErrorKind::EnvRequired,
+            }
+        } else if err.downcast_ref::<strict::UnknownFunction>().is_some() {
+            ErrorKind::UnknownFunction
+        } else if err.downcast_ref::<loader::IncludeCycle>().is_some() {
+            ErrorKind::IncludeCycle
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorEnvelope<'a> {
+    error: ErrorReport<'a>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorReport<'a> {
+    kind: ErrorKind,
+    message: String,
+    location: Option<&'a Location>,
+    /// for `not_trusted`, what it takes to trust the directory, so integrations can offer to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trust: Option<TrustRequired<'a>>,
+}
+
+/// A directory needing `shadowenv trust`: the command, run from within `dir`, trusts it.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct TrustRequired<'a> {
+    dir: &'a str,
+    command: [&'static str; 2],
+}
+
+impl TrustRequired<'_> {
+    fn of(err: &Error) -> Option<TrustRequired<'_>> {
+        err.downcast_ref::<trust::NotTrusted>()
+            .map(|e| TrustRequired {
+                dir: &e.not_trusted_dir_path,
+                command: ["shadowenv", "trust"],
+            })
+    }
+}
+
+/// Describe a hook error for JSON consumers, who otherwise only get prose on stderr.
+pub fn format_hook_error_json(err: &Error, pretty: bool) -> String {
+    let envelope = error_envelope(err);
+    if pretty {
+        serde_json::to_string_pretty(&envelope).unwrap()
+    } else {
+        serde_json::to_string(&envelope).unwrap()
+    }
+}
+
+pub fn hook_error_json(err: &Error) -> serde_json::Value {
+    serde_json::to_value(error_envelope(err)).unwrap()
+}
+
+fn error_envelope(err: &Error) -> ErrorEnvelope<'_> {
+    let report = match (
+        err.downcast_ref::<ShadowlispError>(),
+        err.downcast_ref::<Abort>(),
+    ) {
+        (Some(e), _) => ErrorReport {
+            kind: ErrorKind::Shadowlisp,
+            message: e.message.clone(),
+            location: e.location.as_ref(),
+            trust: None,
+        },
+        (_, Some(e)) => ErrorReport {
+            kind: ErrorKind::of(err),
+            // the message given to `error` is the program's own, so it's passed on untouched.
+            message: match &e.reason {
+                AbortReason::Error { message } => message.clone(),
+                _ => err.to_string(),
+            },
+            location: e.location.as_ref(),
+            trust: None,
+        },
+        _ => ErrorReport {
+            kind: ErrorKind::of(err),
+            message: err.to_string(),
+            location: err
+                .downcast_ref::<strict::UnknownFunction>()
+                .and_then(|e| e.location.as_ref()),
+            trust: TrustRequired::of(err),
+        },
+    };
+    ErrorEnvelope { error: report }
+}
+
+/// What this binary supports, for integrations to check before relying on something newer
+/// versions added.
+#[derive(Serialize, Debug)]
+pub struct Capabilities {
+    pub version: &'static str,
+    pub output_formats: &'static [&'static str],
+    pub builtins: Vec<String>,
+    pub data_format_version: u32,
+}
+
+pub fn capabilities() -> Capabilities {
+    Capabilities {
+        version: env!("CARGO_PKG_VERSION"),
+        output_formats: cli::OUTPUT_FORMATS,
+        builtins: ShadowLang::builtins(),
+        data_format_version: undo::DATA_FORMAT_VERSION,
+    }
+}
+
+/// The line written to SHADOWENV_EVENT_FD after the hook has changed the environment, for
+/// long-running processes watching it. Deactivation has no hash or features.
+#[derive(Serialize, Debug)]
+pub struct Event {
+    pub event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub features: Option<Vec<String>>,
+}
+
+impl Event {
+    pub fn new(activated: bool, hash: String, features: HashSet<Feature>) -> Self {
+        if !activated {
+            return Event {
+                event: "deactivated",
+                hash: None,
+                features: None,
+            };
+        }
+        let mut features: Vec<String> = features.iter().map(|f| f.to_string()).collect();
+        features.sort();
+        Event {
+            event: "activated",
+            hash: Some(hash),
+            features: Some(features),
+        }
+    }
+}
+
+/// The porcelain record telling machine consumers which directory needs trusting, if that's what
+/// went wrong: `4 : <dir> : <command>`, separated as the hook's other porcelain records are.
+pub fn format_hook_error_porcelain(err: &Error) -> Option<String> {
+    TrustRequired::of(err)
+        .map(|trust| format!("\x04\x1F{}\x1F{}\x1E", trust.dir, trust.command.join(" ")))
+}
+
 pub fn handle_hook_error(err: Error, shellpid: u32, silent: bool) -> i32 {
     if silent {
         return 1;
@@ -37,28 +219,169 @@ pub fn handle_hook_error(err: Error, shellpid: u32, silent: bool) -> i32 {
     return 1;
 }
 
-pub fn print_activation_to_tty(activated: bool, features: HashSet<Feature>) {
-    if !should_print_activation() {
-        return;
+/// Write the activation banner, followed by any notes queued by `info`. This is meant for stderr:
+/// stdout is for the shell to evaluate.
+pub fn write_activation(
+    err: &mut dyn Write,
+    activated: bool,
+    features: HashSet<Feature>,
+    notes: &[String],
+) -> io::Result<()> {
+    let banner = if compact_banner() {
+        format_compact_activation(
+            activated,
+            features,
+            feature_display_limit(),
+            color_enabled(),
+        )
+    } else {
+        format_activation(activated, features, feature_display_limit())
+    };
+    writeln!(err, "{}", banner)?;
+    for note in notes {
+        writeln!(err, "{}", format_note(note))?;
     }
-    if activated {
-        if features.len() == 0 {
-            eprint!("\x1b[1;34mactivated {}", SHADOWENV);
-        } else {
-            let feature_list = features
-                .iter()
-                .map(|s| format!("{}", s))
-                .collect::<Vec<String>>()
-                .join(", ");
-            eprint!(
-                "\x1b[1;34mactivated {} \x1b[1;34m({})",
-                SHADOWENV, feature_list
-            );
-        }
+    Ok(())
+}
+
+fn format_note(note: &str) -> String {
+    format!("{} \x1b[34m{}\x1b[0m", SHADOWENV, note)
+}
+
+/// How long each phase of `hook::load_env` took, reported to stderr when SHADOWENV_TIMING is set.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// finding, trust-checking and reading the `.shadowenv.d`
+    pub load: Duration,
+    pub hash: Duration,
+    pub eval: Duration,
+    pub source_dir: Option<String>,
+    pub files: usize,
+}
+
+/// Whether to report how long each phase of loading took, set by SHADOWENV_TIMING.
+pub fn timing_enabled() -> bool {
+    match env::var("SHADOWENV_TIMING") {
+        Ok(v) => !v.is_empty() && v != "0",
+        Err(_) => false,
+    }
+}
+
+pub fn print_timings(timings: &Timings) {
+    eprintln!("{}", format_timings(timings));
+}
+
+fn format_timings(timings: &Timings) -> String {
+    let source = match &timings.source_dir {
+        Some(dir) => format!("{} ({} files)", dir, timings.files),
+        None => "no .shadowenv.d".to_string(),
+    };
+    format!(
+        "{} timing: {}: load {:.3}ms, hash {:.3}ms, eval {:.3}ms",
+        SHADOWENV,
+        source,
+        timings.load.as_secs_f64() * 1000.0,
+        timings.hash.as_secs_f64() * 1000.0,
+        timings.eval.as_secs_f64() * 1000.0,
+    )
+}
+
+/// Write the warnings queued by `warn` in shadowlisp programs. Like the banner, these are meant for
+/// stderr so they can't end up in anything the shell evaluates.
+pub fn write_warnings(err: &mut dyn Write, warnings: &[String]) -> io::Result<()> {
+    for warning in warnings {
+        writeln!(err, "{}", format_warning(warning))?;
+    }
+    Ok(())
+}
+
+fn format_warning(warning: &str) -> String {
+    format!("{} \x1b[1;33mwarning: {}\x1b[0m", SHADOWENV, warning)
+}
+
+fn format_activation(activated: bool, features: HashSet<Feature>, limit: Option<usize>) -> String {
+    if !activated {
+        return format!("\x1b[1;34mdeactivated {}\x1b[1;34m\x1b[0m", SHADOWENV);
+    }
+    if features.is_empty() {
+        return format!("\x1b[1;34mactivated {}\x1b[0m", SHADOWENV);
+    }
+
+    let (features, hidden) = shown_features(features, limit);
+    let mut feature_list = features.join(", ");
+    if hidden > 0 {
+        feature_list += &format!(" (+{} more)", hidden);
+    }
+    format!(
+        "\x1b[1;34mactivated {} \x1b[1;34m({})\x1b[0m",
+        SHADOWENV, feature_list
+    )
+}
+
+/// The features to list in an activation banner, and how many more there are beyond `limit`.
+fn shown_features(features: HashSet<Feature>, limit: Option<usize>) -> (Vec<String>, usize) {
+    let mut features = features
+        .iter()
+        .map(|s| format!("{}", s))
+        .collect::<Vec<String>>();
+    // sorted, so that the same features are shown (and hidden) every time.
+    features.sort();
+    let hidden = match limit {
+        Some(limit) if features.len() > limit => features.split_off(limit).len(),
+        _ => 0,
+    };
+    (features, hidden)
+}
+
+/// The one-line banner for minimal prompts, `shadowenv: go node (+2)`, listing the same features as
+/// `format_activation` does. `color` is off under NO_COLOR.
+fn format_compact_activation(
+    activated: bool,
+    features: HashSet<Feature>,
+    limit: Option<usize>,
+    color: bool,
+) -> String {
+    let prefix = if color {
+        "\x1b[1;34mshadowenv:\x1b[0m"
     } else {
-        eprint!("\x1b[1;34mdeactivated {}\x1b[1;34m", SHADOWENV);
+        "shadowenv:"
+    };
+    if !activated {
+        return format!("{} deactivated", prefix);
+    }
+    if features.is_empty() {
+        return format!("{} activated", prefix);
+    }
+
+    let (features, hidden) = shown_features(features, limit);
+    let mut banner = format!("{} {}", prefix, features.join(" "));
+    if hidden > 0 {
+        banner += &format!(" (+{})", hidden);
+    }
+    banner
+}
+
+/// Whether to use the compact banner, set by SHADOWENV_BANNER=compact.
+fn compact_banner() -> bool {
+    env::var("SHADOWENV_BANNER").as_deref() == Ok("compact")
+}
+
+/// Whether to color output, which https://no-color.org's NO_COLOR turns off.
+fn color_enabled() -> bool {
+    !matches!(env::var("NO_COLOR").as_deref(), Ok(v) if !v.is_empty())
+}
+
+/// How many features to list in the activation message, configured by
+/// SHADOWENV_FEATURE_DISPLAY_LIMIT. A limit of 0 lists them all.
+fn feature_display_limit() -> Option<usize> {
+    let limit = env::var("SHADOWENV_FEATURE_DISPLAY_LIMIT")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_FEATURE_DISPLAY_LIMIT);
+    match limit {
+        0 => None,
+        n => Some(n),
     }
-    eprintln!("\x1b[0m");
 }
 
 fn backticks_to_bright_green(err: Error) -> String {
@@ -142,7 +465,8 @@ fn create_cooldown_sentinel(path: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-fn should_print_activation() -> bool {
+/// Whether to show the activation banner: only on a terminal, and unless SHADOWENV_SILENT is set.
+pub fn should_print_activation() -> bool {
     let configured_to_print: bool;
     match env::var("SHADOWENV_SILENT") {
         Ok(value) => match value.to_lowercase().as_str() {
@@ -153,3 +477,282 @@ fn should_print_activation() -> bool {
     };
     return is(Stream::Stderr) && configured_to_print;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn features(names: &[&str]) -> HashSet<Feature> {
+        names
+            .iter()
+            .map(|n| Feature::new(n.to_string(), None))
+            .collect()
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        for format in &[
+            "posix",
+            "fish",
+            "porcelain",
+            "powershell",
+            "json",
+            "pretty-json",
+        ] {
+            assert!(capabilities.output_formats.contains(format), "{}", format);
+            // each is a flag the hook actually takes.
+            let args = vec![
+                "shadowenv".to_string(),
+                "hook".to_string(),
+                format!("--{}", format),
+            ];
+            assert!(cli::app().get_matches_from_safe(args).is_ok(), "{}", format);
+        }
+
+        for builtin in &[
+            "env/set",
+            "env/prepend-to-pathlist",
+            "provide",
+            "when",
+            "with-feature",
+        ] {
+            assert!(
+                capabilities.builtins.iter().any(|b| b == builtin),
+                "{}",
+                builtin
+            );
+        }
+        // what ketos provides isn't ours to report.
+        assert!(!capabilities
+            .builtins
+            .iter()
+            .any(|b| b == "define" || b == "concat"));
+
+        let json = serde_json::to_value(&capabilities).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(json["data_format_version"], undo::DATA_FORMAT_VERSION);
+    }
+
+    #[test]
+    fn test_format_activation_truncates_features() {
+        let output = format_activation(
+            true,
+            features(&["go", "node", "python", "ruby", "rust", "java", "zig"]),
+            Some(5),
+        );
+        assert_eq!(
+            output,
+            format!(
+                "\x1b[1;34mactivated {} \x1b[1;34m(go, java, node, python, ruby (+2 more))\x1b[0m",
+                SHADOWENV
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_activation_within_limit() {
+        let output = format_activation(true, features(&["ruby", "node"]), Some(2));
+        assert_eq!(
+            output,
+            format!(
+                "\x1b[1;34mactivated {} \x1b[1;34m(node, ruby)\x1b[0m",
+                SHADOWENV
+            )
+        );
+
+        let output = format_activation(true, features(&["ruby", "node", "go"]), None);
+        assert_eq!(
+            output,
+            format!(
+                "\x1b[1;34mactivated {} \x1b[1;34m(go, node, ruby)\x1b[0m",
+                SHADOWENV
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_compact_activation() {
+        let all = &["go", "node", "python", "ruby", "rust", "java", "zig"];
+        assert_eq!(
+            format_activation(true, features(all), Some(5)),
+            format!(
+                "\x1b[1;34mactivated {} \x1b[1;34m(go, java, node, python, ruby (+2 more))\x1b[0m",
+                SHADOWENV
+            )
+        );
+        assert_eq!(
+            format_compact_activation(true, features(all), Some(5), true),
+            "\x1b[1;34mshadowenv:\x1b[0m go java node python ruby (+2)"
+        );
+        assert_eq!(
+            format_compact_activation(true, features(all), Some(5), false),
+            "shadowenv: go java node python ruby (+2)"
+        );
+        assert_eq!(
+            format_compact_activation(true, features(&["ruby", "node"]), None, false),
+            "shadowenv: node ruby"
+        );
+        assert_eq!(
+            format_compact_activation(true, features(&[]), None, false),
+            "shadowenv: activated"
+        );
+        assert_eq!(
+            format_compact_activation(false, features(&["ruby"]), None, false),
+            "shadowenv: deactivated"
+        );
+    }
+
+    #[test]
+    fn test_format_warning() {
+        assert_eq!(
+            format_warning("RUBYOPT is deprecated"),
+            format!(
+                "{} \x1b[1;33mwarning: RUBYOPT is deprecated\x1b[0m",
+                SHADOWENV
+            )
+        );
+    }
+
+    #[test]
+    fn test_format_timings() {
+        let timings = Timings {
+            load: Duration::from_micros(1250),
+            hash: Duration::from_micros(40),
+            eval: Duration::from_millis(12),
+            source_dir: Some("/src/project".to_string()),
+            files: 3,
+        };
+        assert_eq!(
+            format_timings(&timings),
+            format!(
+                "{} timing: /src/project (3 files): load 1.250ms, hash 0.040ms, eval 12.000ms",
+                SHADOWENV
+            )
+        );
+        assert_eq!(
+            format_timings(&Timings::default()),
+            format!(
+                "{} timing: no .shadowenv.d: load 0.000ms, hash 0.000ms, eval 0.000ms",
+                SHADOWENV
+            )
+        );
+    }
+
+    #[test]
+    fn test_not_trusted_error_json() {
+        let err: Error = trust::NotTrusted {
+            not_trusted_dir_path: "/src/project".to_string(),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"not_trusted","message":"directory: '/src/project' contains untrusted shadowenv program: `shadowenv help trust` to learn more.","location":null,"trust":{"dir":"/src/project","command":["shadowenv","trust"]}}}"#
+        );
+        assert_eq!(
+            format_hook_error_porcelain(&err),
+            Some("\x04\x1F/src/project\x1Fshadowenv trust\x1E".to_string())
+        );
+        assert_eq!(
+            format_hook_error_porcelain(&format_err!("something else")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_shadowlisp_error_json() {
+        let err: Error = ShadowlispError {
+            message: "parse error: unexpected end of file".to_string(),
+            location: Some(Location {
+                file: "500_app.lisp".to_string(),
+                line: 3,
+                col: 7,
+            }),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"shadowlisp","message":"parse error: unexpected end of file","location":{"file":"500_app.lisp","line":3,"col":7}}}"#
+        );
+    }
+
+    #[test]
+    fn test_aborted_error_json() {
+        let err: Error = Abort {
+            reason: AbortReason::Error {
+                message: "Please run `bin/setup` first".to_string(),
+            },
+            location: Some(Location {
+                file: "500_app.lisp".to_string(),
+                line: 2,
+                col: 3,
+            }),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"aborted","message":"Please run `bin/setup` first","location":{"file":"500_app.lisp","line":2,"col":3}}}"#
+        );
+    }
+
+    #[test]
+    fn test_env_required_error_json() {
+        let err: Error = Abort {
+            reason: AbortReason::EnvRequired {
+                name: "AWS_PROFILE".to_string(),
+                message: None,
+            },
+            location: Some(Location {
+                file: "500_app.lisp".to_string(),
+                line: 1,
+                col: 1,
+            }),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"env_required","message":"requires `AWS_PROFILE` to be set, but it isn't (500_app.lisp:1)","location":{"file":"500_app.lisp","line":1,"col":1}}}"#
+        );
+    }
+
+    #[test]
+    fn test_unknown_function_error_json() {
+        let err: Error = strict::UnknownFunction {
+            name: "env/sett".to_string(),
+            suggestion: Some("env/set".to_string()),
+            location: Some(Location {
+                file: "500_app.lisp".to_string(),
+                line: 1,
+                col: 1,
+            }),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"unknown_function","message":"unknown function `env/sett` (500_app.lisp:1): did you mean `env/set`?","location":{"file":"500_app.lisp","line":1,"col":1}}}"#
+        );
+    }
+
+    #[test]
+    fn test_include_cycle_error_json() {
+        let err: Error = loader::IncludeCycle {
+            path: "/src/shared/.shadowenv.d".to_string(),
+        }
+        .into();
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"include_cycle","message":"include cycle detected: `/src/shared/.shadowenv.d` ends up including itself","location":null}}"#
+        );
+    }
+
+    #[test]
+    fn test_other_error_json() {
+        let err: Error = io::Error::new(io::ErrorKind::PermissionDenied, "denied").into();
+        assert_eq!(ErrorKind::of(&err), ErrorKind::Other);
+        assert_eq!(
+            format_hook_error_json(&err, false),
+            r#"{"error":{"kind":"other","message":"denied","location":null}}"#
+        );
+    }
+}
@@ -1,26 +1,135 @@
-use failure::Error;
+use failure::{Error, Fail};
 
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::str::FromStr;
 
 use crate::features::Feature;
+use crate::hash::Hash;
 use crate::undo;
 
+/// Variables set by CI providers, any of which being set means we're running under CI. `CI` covers
+/// most providers (GitHub Actions, Buildkite, CircleCI, GitLab, Travis...), but not all of them.
+pub const CI_ENV_VARS: &[&str] = &[
+    "CI",
+    "CONTINUOUS_INTEGRATION",
+    "BUILD_NUMBER",
+    "GITHUB_ACTIONS",
+    "BUILDKITE",
+    "CIRCLECI",
+    "GITLAB_CI",
+    "TRAVIS",
+    "JENKINS_URL",
+    "TEAMCITY_VERSION",
+    "TF_BUILD",
+];
+
+/// Pathlists which a program may not leave empty (when they weren't already) unless it says so
+/// with `env/allow-empty`: without a PATH, the shell can't find any commands.
+pub const GUARDED_PATHLISTS: &[&str] = &["PATH"];
+
+#[derive(Fail, Debug)]
+#[fail(
+    display = "this would leave `{0}` empty: call `(env/allow-empty \"{0}\")` first if that's intended",
+    name
+)]
+pub struct EmptiedPathlist {
+    pub name: String,
+}
+
+/// Convert an env, as from `env::vars_os`, into the strings the rest of shadowenv works with.
+/// `env::vars` would panic on values which aren't valid UTF-8; instead, the invalid sequences are
+/// replaced with U+FFFD, which is what programs see. Such variables are never changed in the
+/// shell, though: see `non_utf8_vars` and `Shadowenv::leave_alone`.
+pub fn env_from_os(vars: impl Iterator<Item = (OsString, OsString)>) -> HashMap<String, String> {
+    vars.map(|(k, v)| {
+        (
+            k.to_string_lossy().into_owned(),
+            v.to_string_lossy().into_owned(),
+        )
+    })
+    .collect()
+}
+
+/// The names of the variables in `vars` whose names or values aren't valid UTF-8, as
+/// `env_from_os` names them.
+pub fn non_utf8_vars<'a>(vars: impl Iterator<Item = &'a (OsString, OsString)>) -> BTreeSet<String> {
+    vars.filter(|(k, v)| k.to_str().is_none() || v.to_str().is_none())
+        .map(|(k, _)| k.to_string_lossy().into_owned())
+        .collect()
+}
+
+/// A variable to set (or unset, if `value` is `None`) in the calling shell.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Export {
+    pub value: Option<String>,
+    /// whether the shell should pass the variable on to its children.
+    pub exported: bool,
+}
+
 #[derive(Debug)]
 pub struct Shadowenv {
     /// the mutated/modified env: the final state we want to be in after eval'ing exports.
     env: HashMap<String, String>,
-    /// the outer env, reconstructed by undoing $__shadowenv_data
+    /// the outer env, reconstructed by undoing $__shadowenv_data. This is captured once, before
+    /// the program runs, and is what deactivation restores.
     unshadowed_env: HashMap<String, String>,
     /// the env inherited from the calling process, untouched.
     initial_env: HashMap<String, String>,
-    /// names of variables which are treated as pathlists by the program
+    /// the $__shadowenv_data of the previous activation, if any.
+    previous_data: undo::Data,
+    /// names of variables which are treated as pathlists by the program. Once a pathlist op
+    /// touches a variable, it's undone as a list, whatever else the program does to it.
     lists: HashSet<String>,
+    /// names of variables whose whole value the program replaced, with `set` or by deduplicating
+    /// it. If they're pathlists, their undo record removes every final entry and puts back every
+    /// original one, as item-by-item changes relative to the original no longer describe them.
+    reset_lists: HashSet<String>,
+    /// names of variables the program set as shell variables, not to be exported
+    unexported: HashSet<String>,
     /// list of features provided by all plugins
     features: HashSet<Feature>,
+    /// messages queued by the program, to be shown to the user after activation
+    warnings: Vec<String>,
+    /// notes queued by the program with `info`, to be shown with the activation banner
+    notes: Vec<String>,
+    /// variables set (or unset, for `None`) with `env/set-exec-only`: only processes started by
+    /// `shadowenv exec` get these, never the interactive shell.
+    exec_only: BTreeMap<String, Option<String>>,
+    /// pathlist entries added with a tag, as (variable, entry) pairs by tag, so that they can be
+    /// removed together later on. Only the program sees these: they aren't exported.
+    pathlist_tags: HashMap<String, Vec<(String, String)>>,
+    /// guarded pathlists the program is allowed to leave empty
+    allowed_empty: HashSet<String>,
+    /// whether the shell we're activating in is a login shell
+    login_shell: bool,
+    /// whether $__shadowenv_data itself is exported, so that subshells inherit it
+    export_data: bool,
+    /// directories handed out by `cache-dir` and `mktempdir` to be removed once we leave this
+    /// activation
+    cache_dirs: BTreeSet<String>,
+    /// variables which aren't valid UTF-8 in the calling shell. We only have a lossy copy of
+    /// their values, so they're never exported or undone, whatever the program does with them.
+    non_utf8: BTreeSet<String>,
+    /// the hash of the activation `shadowenv unload` undid, to be left inactive.
+    unloaded: Option<Hash>,
+    /// the project directory of the source this activates, once it has been run.
+    root: Option<String>,
+    /// the pid of the shell we're activating in, if we were told it.
+    shell_pid: Option<u32>,
     target_hash: u64,
 }
 
+/// A sorted view of one side of an activation, for comparing what a program changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub vars: BTreeMap<String, String>,
+    /// names of variables set on the other side of the pair, but not on this one.
+    pub unset: BTreeSet<String>,
+}
+
 impl Shadowenv {
     pub fn load_shadowenv_data_or_legacy_fallback(fallback_data: Option<String>) -> String {
         match env::var("__shadowenv_data") {
@@ -34,14 +143,29 @@ impl Shadowenv {
         shadowenv_data: undo::Data,
         target_hash: u64,
     ) -> Shadowenv {
-        let unshadowed_env = Shadowenv::unshadow(&env, shadowenv_data);
+        let unshadowed_env = Shadowenv::unshadow(&env, shadowenv_data.clone());
 
         Shadowenv {
             env: unshadowed_env.clone(),
             unshadowed_env,
             initial_env: env,
+            previous_data: shadowenv_data,
             lists: HashSet::new(),
+            reset_lists: HashSet::new(),
+            unexported: HashSet::new(),
             features: HashSet::new(),
+            warnings: vec![],
+            notes: vec![],
+            exec_only: BTreeMap::new(),
+            pathlist_tags: HashMap::new(),
+            allowed_empty: HashSet::new(),
+            login_shell: false,
+            export_data: true,
+            cache_dirs: BTreeSet::new(),
+            non_utf8: BTreeSet::new(),
+            unloaded: None,
+            root: None,
+            shell_pid: None,
             target_hash,
         }
     }
@@ -52,7 +176,7 @@ impl Shadowenv {
     ) -> HashMap<String, String> {
         let mut result = env.clone();
         for scalar in shadowenv_data.scalars {
-            if env_get(&result, scalar.name.clone()) == scalar.current {
+            if scalar.unexported || env_get(&result, scalar.name.clone()) == scalar.current {
                 env_set(&mut result, scalar.name, scalar.original);
             }
         }
@@ -60,8 +184,11 @@ impl Shadowenv {
             for addition in list.additions {
                 env_remove_from_pathlist(&mut result, list.name.clone(), addition);
             }
-            // TODO(burke): figure out a way to preserve approximate ordering
-            for deletion in list.deletions {
+            // deletions are recorded in their original order, so prepending them back in reverse
+            // keeps their order relative to each other, no matter which layer removed which entry.
+            // They do all come back at the front, rather than where each one was. A replaced list
+            // records every original entry as a deletion, so it gets back exactly its old value.
+            for deletion in list.deletions.into_iter().rev() {
                 env_prepend_to_pathlist(&mut result, list.name.clone(), deletion);
             }
         }
@@ -83,6 +210,15 @@ impl Shadowenv {
         let mut data = undo::Data::new();
 
         for (varname, final_value) in changes {
+            if self.unexported.contains(&varname) {
+                data.scalars.push(undo::Scalar {
+                    original: self.unshadowed_env.get(&varname).cloned(),
+                    current: final_value,
+                    unexported: true,
+                    name: varname,
+                });
+                continue;
+            }
             if self.lists.contains(&varname) {
                 let unshadowed_parts: Vec<&str> = match self.unshadowed_env.get(&varname) {
                     Some(s) => s.split(':').collect(),
@@ -92,48 +228,223 @@ impl Shadowenv {
                     Some(s) => s.split(':').collect(),
                     None => vec![],
                 };
-                let (additions, deletions) = diff_vecs(unshadowed_parts, final_parts);
+                let (additions, deletions) = if self.reset_lists.contains(&varname) {
+                    (
+                        final_parts.into_iter().map(String::from).collect(),
+                        unshadowed_parts.into_iter().map(String::from).collect(),
+                    )
+                } else {
+                    diff_vecs(unshadowed_parts, final_parts)
+                };
                 data.add_list(varname, additions, deletions);
-            } else {
-                let unshadowed_value = self.unshadowed_env.get(&varname).map(|s| s.to_string());
-                data.add_scalar(varname, unshadowed_value, final_value);
+                continue;
             }
+            let unshadowed_value = self.unshadowed_env.get(&varname).map(|s| s.to_string());
+            data.add_scalar(varname, unshadowed_value, final_value);
         }
+        data.cache_dirs = self.cache_dirs.iter().cloned().collect();
         data
     }
 
-    fn format_shadowenv_data(&self) -> Result<String, Error> {
-        let d = self.shadowenv_data();
-        Ok(format!("{:016x}:", self.target_hash) + &serde_json::to_string(&d)?)
+    pub(crate) fn format_shadowenv_data(&self) -> Result<String, Error> {
+        let mut d = self.shadowenv_data();
+        if let Some(root) = &self.root {
+            d.summary = Some(undo::Summary::of(root, &d).to_string());
+        }
+        d.unloaded = self.unloaded.as_ref().map(Hash::to_string);
+        // opt-in, as compressed data hides the summary from shells reading it directly.
+        let compress = !matches!(
+            self.get_outer("SHADOWENV_COMPRESS_DATA").as_deref(),
+            None | Some("") | Some("0")
+        );
+        Ok(format!("{:016x}:", self.target_hash) + &d.encode(compress)?)
     }
 
-    pub fn exports(&self) -> Result<HashMap<String, Option<String>>, Error> {
-        let mut changes: HashMap<String, Option<String>> = HashMap::new();
-        let varnames = self.all_relevant_varnames();
+    /// The variables to set in the calling shell: everything whose value differs from the
+    /// inherited env, along with $__shadowenv_data. Unexported variables are always included,
+    /// since the hook can't tell whether the shell already has them.
+    pub fn exports(&self) -> Result<HashMap<String, Export>, Error> {
+        let mut changes: HashMap<String, Export> = HashMap::new();
+        let mut varnames = self.all_relevant_varnames();
+        let previously_unexported = self.previously_unexported();
+        varnames.extend(previously_unexported.iter().map(|s| s.to_string()));
 
-        changes.insert(
-            "__shadowenv_data".to_string(),
-            Some(self.format_shadowenv_data()?),
-        );
+        changes.insert("__shadowenv_data".to_string(), self.data_export()?);
 
         for varname in varnames {
             let a = self.env.get(&varname);
             let b = self.initial_env.get(&varname);
-            if a != b {
-                changes.insert(varname, a.cloned());
+            if a != b
+                || self.unexported.contains(&varname)
+                || previously_unexported.contains(varname.as_str())
+            {
+                changes.insert(varname.clone(), self.export(&varname));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Like `exports`, but relative to the env as the previous activation left it rather than to
+    /// the inherited env, leaving out variables which already have their new value. Applying these
+    /// on top of the previous activation ends up in the same state as applying `exports` would.
+    /// Pathlists the previous activation touched are always included, since the undo data doesn't
+    /// record their exact value.
+    pub fn delta_exports(&self) -> Result<HashMap<String, Export>, Error> {
+        let mut previous_env = self.initial_env.clone();
+        for scalar in &self.previous_data.scalars {
+            env_set(
+                &mut previous_env,
+                scalar.name.clone(),
+                scalar.current.clone(),
+            );
+        }
+        let previous_lists: HashSet<&str> = self
+            .previous_data
+            .lists
+            .iter()
+            .map(|l| l.name.as_str())
+            .collect();
+        let previously_unexported = self.previously_unexported();
+
+        let mut changes: HashMap<String, Export> = HashMap::new();
+        let mut varnames = self.all_relevant_varnames();
+        varnames.extend(previous_env.keys().map(String::from));
+
+        changes.insert("__shadowenv_data".to_string(), self.data_export()?);
+
+        for varname in varnames {
+            let a = self.env.get(&varname);
+            let b = previous_env.get(&varname);
+            let was_unexported = previously_unexported.contains(varname.as_str());
+            if a != b
+                || previous_lists.contains(varname.as_str())
+                || was_unexported != self.unexported.contains(&varname)
+            {
+                changes.insert(varname.clone(), self.export(&varname));
             }
         }
         Ok(changes)
     }
 
+    fn export(&self, varname: &str) -> Export {
+        Export {
+            value: self.env.get(varname).cloned(),
+            exported: !self.unexported.contains(varname),
+        }
+    }
+
+    fn data_export(&self) -> Result<Export, Error> {
+        Ok(Export {
+            value: Some(self.format_shadowenv_data()?),
+            exported: self.export_data,
+        })
+    }
+
+    /// Variables the previous activation set without exporting them.
+    fn previously_unexported(&self) -> HashSet<&str> {
+        self.previous_data
+            .scalars
+            .iter()
+            .filter(|s| s.unexported)
+            .map(|s| s.name.as_str())
+            .collect()
+    }
+
+    /// The environment as it was before activation: the calling process' env with any previously
+    /// active shadowenv undone.
+    pub fn snapshot_before(&self) -> Snapshot {
+        snapshot(&self.unshadowed_env, &self.env)
+    }
+
+    /// The environment as it will be once the exports have been applied.
+    pub fn snapshot_after(&self) -> Snapshot {
+        snapshot(&self.env, &self.unshadowed_env)
+    }
+
     pub fn set(&mut self, a: &str, b: Option<&str>) {
+        self.unexported.remove(a);
+        self.reset_lists.insert(a.to_string());
         env_set(&mut self.env, a.to_string(), b.map(|s| s.to_string()))
     }
 
+    /// Set a shell variable which isn't passed on to the shell's children. Unsetting is the same
+    /// as with `set`.
+    pub fn set_unexported(&mut self, a: &str, b: Option<&str>) {
+        self.set(a, b);
+        if b.is_some() {
+            self.unexported.insert(a.to_string());
+        }
+    }
+
+    /// Set a variable for commands run with `shadowenv exec` only. It's left out of the exports,
+    /// so the interactive shell never sees it, and it isn't visible to `get` either.
+    pub fn set_exec_only(&mut self, a: &str, b: Option<&str>) {
+        self.exec_only
+            .insert(a.to_string(), b.map(|s| s.to_string()));
+    }
+
+    pub fn exec_only(&self) -> &BTreeMap<String, Option<String>> {
+        &self.exec_only
+    }
+
+    /// Replace the whole of a pathlist with `items`, in that order. An empty list unsets it.
+    pub fn set_pathlist(&mut self, a: &str, items: &[&str]) {
+        self.inform_list(a);
+        let value = items.join(":");
+        self.set(a, if items.is_empty() { None } else { Some(&value) })
+    }
+
+    /// The env as the program has left it so far.
+    pub fn current_env(&self) -> &HashMap<String, String> {
+        &self.env
+    }
+
     pub fn get(&self, a: &str) -> Option<String> {
         env_get(&self.env, a.to_string())
     }
 
+    /// Prepend `b` to the pathlist `a`, remembering it under `tag` for `remove_pathlist_tag`.
+    pub fn prepend_to_pathlist_tagged(&mut self, a: &str, b: &str, tag: &str) {
+        self.prepend_to_pathlist(a, b);
+        let entries = self.pathlist_tags.entry(tag.to_string()).or_default();
+        let entry = (a.to_string(), b.to_string());
+        if !entries.contains(&entry) {
+            entries.push(entry);
+        }
+    }
+
+    /// Remove every pathlist entry added with `tag`, from whichever pathlists they were added to.
+    /// Tags nothing was added with are fine, and remove nothing.
+    pub fn remove_pathlist_tag(&mut self, tag: &str) {
+        for (a, b) in self.pathlist_tags.remove(tag).unwrap_or_default() {
+            self.remove_from_pathlist(&a, &b);
+        }
+    }
+
+    /// Allow the program to leave `a` empty, even if it's one of the `GUARDED_PATHLISTS`.
+    pub fn allow_empty(&mut self, a: &str) {
+        self.allowed_empty.insert(a.to_string());
+    }
+
+    /// Refuse the result of a program which emptied one of the `GUARDED_PATHLISTS`, without having
+    /// said it meant to. Only the final value counts, so emptying PATH on the way to rebuilding it
+    /// is fine.
+    pub fn check_guarded_pathlists(&self) -> Result<(), EmptiedPathlist> {
+        let is_empty =
+            |env: &HashMap<String, String>, name: &str| env.get(name).is_none_or(|v| v.is_empty());
+        for name in GUARDED_PATHLISTS {
+            if !self.allowed_empty.contains(*name)
+                && !is_empty(&self.unshadowed_env, name)
+                && is_empty(&self.env, name)
+            {
+                return Err(EmptiedPathlist {
+                    name: name.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
     pub fn remove_from_pathlist(&mut self, a: &str, b: &str) {
         self.inform_list(a);
         env_remove_from_pathlist(&mut self.env, a.to_string(), b.to_string())
@@ -154,15 +465,213 @@ impl Shadowenv {
         env_prepend_to_pathlist(&mut self.env, a.to_string(), b.to_string())
     }
 
+    /// Add `b` to the end of `a`, a list of items joined by `separator`, unless it's already in
+    /// it. Unlike pathlists, these are undone as scalars, restoring the previous value exactly.
+    pub fn append_with_separator(&mut self, a: &str, b: &str, separator: &str) {
+        env_add_with_separator(&mut self.env, a, b, separator, false)
+    }
+
+    /// Like `append_with_separator`, but adds `b` to the start of the list.
+    pub fn prepend_with_separator(&mut self, a: &str, b: &str, separator: &str) {
+        env_add_with_separator(&mut self.env, a, b, separator, true)
+    }
+
     pub fn add_feature(&mut self, name: &str, version: Option<&str>) {
         let feature = Feature::new(name.to_string(), version.map(|s| s.to_string()));
         self.features.insert(feature);
     }
 
+    /// Whether some feature provided so far is `name`, at `min_version` or newer.
+    pub fn provides(&self, name: &str, min_version: Option<&str>) -> bool {
+        self.features
+            .iter()
+            .any(|feature| feature.satisfies(name, min_version))
+    }
+
+    /// The hash of the source being activated, as recorded in $__shadowenv_data. It's computed
+    /// from the source alone, before the program runs, so nothing the program does can change it.
+    pub fn target_hash(&self) -> Hash {
+        Hash {
+            hash: self.target_hash,
+        }
+    }
+
+    /// Whether to export $__shadowenv_data, which is the default. Subshells which don't inherit it
+    /// still inherit the activated env, but can't tell that it's active, or how to undo it.
+    pub fn set_export_data(&mut self, export_data: bool) {
+        self.export_data = export_data;
+    }
+
+    /// Never export or undo the variables in `non_utf8` (see `non_utf8_vars`), warning about
+    /// those the program changed anyway.
+    pub fn leave_alone(&mut self, non_utf8: BTreeSet<String>) {
+        for name in &non_utf8 {
+            if self.env.get(name) != self.initial_env.get(name) {
+                self.warnings.push(format!(
+                    "`{}` isn't valid UTF-8, so shadowenv left it as it was",
+                    name
+                ));
+            }
+        }
+        self.non_utf8 = non_utf8;
+    }
+
+    /// Record the project directory of the source this activates, for the summary in
+    /// $__shadowenv_data.
+    pub fn set_root(&mut self, root: &str) {
+        self.root = Some(root.to_string());
+    }
+
+    /// Mark the activation with the given hash as unloaded, so that the hook leaves it inactive
+    /// (see `undo::Data::unloaded`).
+    pub fn set_unloaded(&mut self, hash: Option<Hash>) {
+        self.unloaded = hash;
+    }
+
+    /// The activation the previous $__shadowenv_data marked as unloaded, if any.
+    pub fn previously_unloaded(&self) -> Result<Option<Hash>, Error> {
+        match &self.previous_data.unloaded {
+            Some(hash) => Ok(Some(Hash::from_str(hash)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_login_shell(&mut self, login_shell: bool) {
+        self.login_shell = login_shell;
+    }
+
+    pub fn is_login_shell(&self) -> bool {
+        self.login_shell
+    }
+
+    pub fn set_shell_pid(&mut self, pid: u32) {
+        self.shell_pid = Some(pid);
+    }
+
+    /// The pid of the shell we're activating in, which is our parent unless we were told
+    /// otherwise.
+    pub fn shell_pid(&self) -> u32 {
+        self.shell_pid
+            .unwrap_or_else(|| unsafe { libc::getppid() } as u32)
+    }
+
+    /// The outer environment's temporary directory, where `mktempdir` puts its directories. It's
+    /// looked up before any activation, which may well have pointed $TMPDIR at one of them.
+    pub fn temp_root(&self) -> PathBuf {
+        match self.get_outer("TMPDIR") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from("/tmp"),
+        }
+    }
+
+    /// Record a directory created by `cache-dir` or `mktempdir`, to be cleaned up when we leave this activation.
+    pub fn add_cache_dir(&mut self, dir: &str) {
+        self.cache_dirs.insert(dir.to_string());
+    }
+
+    /// The directories this activation was handed by `cache-dir` and `mktempdir`.
+    pub fn cache_dirs(&self) -> &BTreeSet<String> {
+        &self.cache_dirs
+    }
+
+    /// Cache directories the previous activation created which this one doesn't use: those of
+    /// an older version of the source, or of a project we've left.
+    pub fn stale_cache_dirs(&self) -> Vec<String> {
+        self.previous_data
+            .cache_dirs
+            .iter()
+            .filter(|dir| !self.cache_dirs.contains(*dir))
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the program left no trace at all: no variables changed, no features provided and
+    /// no warnings or notes to show, and nothing set for `exec`.
+    pub fn changes_nothing(&self) -> bool {
+        self.shadowenv_data() == undo::Data::new()
+            && self.features.is_empty()
+            && self.warnings.is_empty()
+            && self.notes.is_empty()
+            && self.exec_only.is_empty()
+    }
+
+    /// Look up a variable in the outer environment, ignoring any changes made by the program.
+    pub fn get_outer(&self, a: &str) -> Option<String> {
+        env_get(&self.unshadowed_env, a.to_string())
+    }
+
+    /// Whether the outer environment looks like it's running under CI. `CI=false` and empty
+    /// values don't count.
+    pub fn is_ci(&self) -> bool {
+        CI_ENV_VARS.iter().any(|varname| {
+            match self.unshadowed_env.get(*varname).map(String::as_str) {
+                None | Some("") | Some("false") => false,
+                Some(_) => true,
+            }
+        })
+    }
+
     pub fn features(&self) -> HashSet<Feature> {
         self.features.iter().cloned().collect()
     }
 
+    pub fn add_warning(&mut self, message: &str) {
+        self.warnings.push(message.to_string());
+    }
+
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    pub fn add_note(&mut self, message: &str) {
+        self.notes.push(message.to_string());
+    }
+
+    pub fn notes(&self) -> &[String] {
+        &self.notes
+    }
+
+    /// Names of variables the previous activation set which no longer hold the value it left
+    /// them with, meaning something else changed them since: scalars whose value differs, and
+    /// pathlists missing one of the entries it added. Unexported variables are never visible to
+    /// us, so they're skipped.
+    pub fn modified_since_activation(&self) -> Vec<String> {
+        let mut names: Vec<String> = vec![];
+        for scalar in &self.previous_data.scalars {
+            if !scalar.unexported && self.initial_env.get(&scalar.name) != scalar.current.as_ref() {
+                names.push(scalar.name.clone());
+            }
+        }
+        for list in &self.previous_data.lists {
+            let items: Vec<&str> = match self.initial_env.get(&list.name) {
+                Some(value) => value.split(':').collect(),
+                None => vec![],
+            };
+            if list.additions.iter().any(|a| !items.contains(&a.as_str())) {
+                names.push(list.name.clone());
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// Remove repeated entries from every pathlist the program touched, keeping the first of each.
+    /// This only affects the final value: deactivation still restores the original.
+    pub fn dedup_pathlists(&mut self) {
+        for varname in &self.lists {
+            if let Some(value) = self.env.get_mut(varname) {
+                let mut seen = HashSet::new();
+                let entries: Vec<&str> = value.split(':').filter(|e| seen.insert(*e)).collect();
+                let deduped = entries.join(":");
+                if deduped != *value {
+                    *value = deduped;
+                    self.reset_lists.insert(varname.clone());
+                }
+            }
+        }
+    }
+
     fn inform_list(&mut self, a: &str) {
         self.lists.insert(a.to_string());
     }
@@ -170,10 +679,22 @@ impl Shadowenv {
     fn all_relevant_varnames(&self) -> HashSet<String> {
         let mut keys: HashSet<String> = self.env.keys().map(String::from).collect();
         keys.extend(self.initial_env.keys().map(String::from));
+        keys.retain(|k| !self.non_utf8.contains(k));
         keys
     }
 }
 
+fn snapshot(env: &HashMap<String, String>, other: &HashMap<String, String>) -> Snapshot {
+    Snapshot {
+        vars: env.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+        unset: other
+            .keys()
+            .filter(|k| !env.contains_key(*k))
+            .cloned()
+            .collect(),
+    }
+}
+
 fn env_set(env: &mut HashMap<String, String>, a: String, b: Option<String>) {
     match b {
         Some(string) => {
@@ -246,6 +767,32 @@ fn env_prepend_to_pathlist(env: &mut HashMap<String, String>, a: String, b: Stri
     env.insert(a, next);
 }
 
+fn env_add_with_separator(
+    env: &mut HashMap<String, String>,
+    a: &str,
+    b: &str,
+    separator: &str,
+    prepend: bool,
+) {
+    let mut items = match env.get(a) {
+        Some(existing) => existing
+            .split(separator)
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<&str>>(),
+        None => vec![],
+    };
+    if items.contains(&b) {
+        return;
+    }
+    if prepend {
+        items.insert(0, b);
+    } else {
+        items.push(b);
+    }
+    let next = items.join(separator);
+    env.insert(a.to_string(), next);
+}
+
 fn diff_vecs(oldvec: Vec<&str>, newvec: Vec<&str>) -> (Vec<String>, Vec<String>) {
     let mut additions: Vec<String> = vec![];
     let mut deletions: Vec<String> = vec![];
@@ -278,8 +825,9 @@ fn diff_vecs(oldvec: Vec<&str>, newvec: Vec<&str>) -> (Vec<String>, Vec<String>)
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::undo::{Data, List, Scalar};
+    use crate::undo::{Data, List, Scalar, Summary};
     use std::collections::HashMap;
+    use std::path::Path;
 
     fn build_shadow_env(env_variables: Vec<(&str, &str)>, data: Data) -> Shadowenv {
         let env = env_variables
@@ -289,6 +837,58 @@ mod tests {
         Shadowenv::new(env, data, 123456789)
     }
 
+    #[test]
+    fn test_summary_roundtrip() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin")], Data::new());
+        shadowenv.set_root("/src/app");
+        shadowenv.set("GOPATH", Some("/go"));
+        shadowenv.set_unexported("PROMPT_TAG", Some("app"));
+        shadowenv.prepend_to_pathlist("PATH", "/go/bin");
+        let blob = shadowenv.format_shadowenv_data().unwrap();
+
+        let summary = Summary::from_shadowenv_data(&blob).unwrap();
+        assert_eq!(summary.root, "/src/app");
+        assert_eq!(summary.vars, vec!["GOPATH", "PATH", "PROMPT_TAG"]);
+        assert_eq!(summary.to_string(), "/src/app;GOPATH,PATH,PROMPT_TAG");
+        assert_eq!(summary.to_string().parse::<Summary>().unwrap(), summary);
+
+        // it's a prefix of the blob, so shells can cut it out without parsing the JSON.
+        assert!(
+            blob.starts_with(r#"00000000075bcd15:{"summary":"/src/app;GOPATH,PATH,PROMPT_TAG","#)
+        );
+
+        let empty = Summary {
+            root: "/src/a;b".to_string(),
+            vars: vec![],
+        };
+        assert_eq!(empty.to_string().parse::<Summary>().unwrap(), empty);
+        assert!("/src/app".parse::<Summary>().is_err());
+        assert!(";PATH".parse::<Summary>().is_err());
+    }
+
+    #[test]
+    fn test_summary_signals_when_the_hook_can_be_skipped() {
+        let mut shadowenv = build_shadow_env(vec![], Data::new());
+        shadowenv.set_root("/src/app");
+        shadowenv.set("GOPATH", Some("/go"));
+        let blob = shadowenv.format_shadowenv_data().unwrap();
+        let summary = Summary::from_shadowenv_data(&blob).unwrap();
+
+        // moving around within the project finds the same .shadowenv.d.
+        assert!(!summary.needs_hook(Path::new("/src/app")));
+        assert!(!summary.needs_hook(Path::new("/src/app/lib/deep")));
+        // another project, or no project at all, need the hook.
+        assert!(summary.needs_hook(Path::new("/src/other")));
+        assert!(summary.needs_hook(Path::new("/src/application")));
+        assert!(summary.needs_hook(Path::new("/")));
+
+        // with nothing active there's no summary, and so nothing to skip.
+        let inactive = Shadowenv::new(HashMap::new(), Data::new(), 0);
+        let blob = inactive.format_shadowenv_data().unwrap();
+        assert_eq!(Summary::from_shadowenv_data(&blob), None);
+        assert_eq!(Summary::from_shadowenv_data(""), None);
+    }
+
     #[test]
     fn test_get_set() {
         let mut shadowenv = build_shadow_env(vec![], Default::default());
@@ -327,16 +927,19 @@ mod tests {
                     name: "VAR_A".to_string(),
                     original: Some("v0".to_string()),
                     current: Some("v2".to_string()),
+                    ..Default::default()
                 },
                 Scalar {
                     name: "VAR_B".to_string(),
                     original: Some("v0".to_string()),
                     current: None,
+                    ..Default::default()
                 },
                 Scalar {
                     name: "VAR_C".to_string(),
                     original: None,
                     current: Some("v3".to_string()),
+                    ..Default::default()
                 },
             ],
             lists: vec![List {
@@ -344,6 +947,7 @@ mod tests {
                 additions: vec!["/path4".to_string(), "/path3".to_string()],
                 deletions: vec!["/path1".to_string()],
             }],
+            ..Default::default()
         };
 
         let expected_formatted_data = r#"00000000075bcd15:{"scalars":[{"name":"VAR_A","original":"v0","current":"v2"},{"name":"VAR_B","original":"v0","current":null},{"name":"VAR_C","original":null,"current":"v3"}],"lists":[{"name":"PATH","additions":["/path4","/path3"],"deletions":["/path1"]}]}"#;
@@ -368,6 +972,394 @@ mod tests {
         .into_iter()
         .collect();
 
-        assert_eq!(shadowenv.exports().unwrap(), expected_export);
+        let exports: HashMap<_, _> = shadowenv
+            .exports()
+            .unwrap()
+            .into_iter()
+            .map(|(k, export)| (k, export.value))
+            .collect();
+        assert_eq!(exports, expected_export);
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let mut shadowenv = build_shadow_env(
+            vec![("VAR_A", "v0"), ("VAR_B", "v0"), ("PATH", "/path1")],
+            Default::default(),
+        );
+        shadowenv.set("VAR_A", Some("v1"));
+        shadowenv.set("VAR_B", None);
+        shadowenv.set("VAR_C", Some("v2"));
+        shadowenv.prepend_to_pathlist("PATH", "/path0");
+
+        let before = shadowenv.snapshot_before();
+        let expected_before: BTreeMap<String, String> = vec![
+            ("PATH".to_string(), "/path1".to_string()),
+            ("VAR_A".to_string(), "v0".to_string()),
+            ("VAR_B".to_string(), "v0".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(before.vars, expected_before);
+        assert_eq!(
+            before.unset,
+            vec!["VAR_C".to_string()].into_iter().collect()
+        );
+
+        let after = shadowenv.snapshot_after();
+        let expected_after: BTreeMap<String, String> = vec![
+            ("PATH".to_string(), "/path0:/path1".to_string()),
+            ("VAR_A".to_string(), "v1".to_string()),
+            ("VAR_C".to_string(), "v2".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(after.vars, expected_after);
+        assert_eq!(after.unset, vec!["VAR_B".to_string()].into_iter().collect());
+    }
+
+    #[test]
+    fn test_snapshot_before_undoes_previous_activation() {
+        let data = Data {
+            scalars: vec![Scalar {
+                name: "VAR_A".to_string(),
+                original: None,
+                current: Some("v1".to_string()),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let shadowenv = build_shadow_env(vec![("VAR_A", "v1")], data);
+
+        assert_eq!(shadowenv.snapshot_before(), Snapshot::default());
+        assert_eq!(shadowenv.snapshot_after(), Snapshot::default());
+    }
+
+    /// Deactivate by feeding the exports of `shadowenv` back in, as the next hook would.
+    fn deactivated(shadowenv: &Shadowenv) -> Shadowenv {
+        let exports = shadowenv.exports().unwrap();
+        let data = exports["__shadowenv_data"].value.clone().unwrap();
+        let data: Data = data.split_once(':').unwrap().1.parse().unwrap();
+        let env = exports
+            .into_iter()
+            .filter_map(|(k, export)| match export.value {
+                // a subshell running the hook doesn't see the shell's unexported variables.
+                Some(v) if export.exported => Some((k, v)),
+                _ => None,
+            })
+            .collect();
+        Shadowenv::new(env, data, 0)
+    }
+
+    #[test]
+    fn test_remove_pathlist_tag() {
+        let mut shadowenv = build_shadow_env(
+            vec![("PATH", "/usr/bin"), ("MANPATH", "/usr/share/man")],
+            Data::new(),
+        );
+        shadowenv.prepend_to_pathlist_tagged("PATH", "/opt/ruby/bin", "ruby");
+        shadowenv.prepend_to_pathlist_tagged("MANPATH", "/opt/ruby/man", "ruby");
+        shadowenv.prepend_to_pathlist_tagged("PATH", "/opt/node/bin", "node");
+        shadowenv.prepend_to_pathlist("PATH", "/project/bin");
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/project/bin:/opt/node/bin:/opt/ruby/bin:/usr/bin".to_string())
+        );
+
+        shadowenv.remove_pathlist_tag("ruby");
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/project/bin:/opt/node/bin:/usr/bin".to_string())
+        );
+        assert_eq!(shadowenv.get("MANPATH"), Some("/usr/share/man".to_string()));
+
+        // the tag is gone with its entries, so re-adding without it isn't affected.
+        shadowenv.prepend_to_pathlist("PATH", "/opt/ruby/bin");
+        shadowenv.remove_pathlist_tag("ruby");
+        shadowenv.remove_pathlist_tag("never-used");
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/opt/ruby/bin:/project/bin:/opt/node/bin:/usr/bin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_compressed_data_restores() {
+        let mut shadowenv = build_shadow_env(
+            vec![("SHADOWENV_COMPRESS_DATA", "1"), ("SECRET", "outer")],
+            Data::new(),
+        );
+        shadowenv.set("SECRET", None);
+        shadowenv.set("ADDED", Some("inner"));
+        let blob = shadowenv.format_shadowenv_data().unwrap();
+        assert!(blob.starts_with(&format!("00000000075bcd15:{}", undo::COMPRESSED_PREFIX)));
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("SECRET"), Some("outer".to_string()));
+        assert_eq!(after.get("ADDED"), None);
+    }
+
+    #[test]
+    fn test_set_pathlist_replaces_and_restores() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.set_pathlist("PATH", &["/opt/a", "/usr/bin", "/opt/b"]);
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/opt/a:/usr/bin:/opt/b".to_string())
+        );
+        shadowenv.set_pathlist("MANPATH", &[]);
+        assert_eq!(shadowenv.get("MANPATH"), None);
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PATH"), Some("/usr/bin:/bin".to_string()));
+    }
+
+    #[test]
+    fn test_emptying_path_is_refused() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.remove_from_pathlist("PATH", "/usr/bin");
+        assert!(shadowenv.check_guarded_pathlists().is_ok());
+        shadowenv.remove_from_pathlist("PATH", "/bin");
+        let err = shadowenv.check_guarded_pathlists().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "this would leave `PATH` empty: call `(env/allow-empty \"PATH\")` first if that's intended"
+        );
+
+        // rebuilding it afterwards is fine.
+        shadowenv.append_to_pathlist("PATH", "/opt/bin");
+        assert!(shadowenv.check_guarded_pathlists().is_ok());
+
+        shadowenv.set("PATH", Some(""));
+        assert!(shadowenv.check_guarded_pathlists().is_err());
+        shadowenv.allow_empty("PATH");
+        assert!(shadowenv.check_guarded_pathlists().is_ok());
+
+        // only PATH is guarded, and only when there was one to begin with.
+        let mut shadowenv = build_shadow_env(vec![("MANPATH", "/usr/share/man")], Data::new());
+        shadowenv.remove_from_pathlist("MANPATH", "/usr/share/man");
+        shadowenv.set("PATH", None);
+        assert!(shadowenv.check_guarded_pathlists().is_ok());
+    }
+
+    #[test]
+    fn test_add_with_separator() {
+        let mut shadowenv = build_shadow_env(
+            vec![("NODE_OPTIONS", "--no-deprecation"), ("RUBYOPT", "-W0")],
+            Data::new(),
+        );
+        shadowenv.append_with_separator("NODE_OPTIONS", "--max-old-space-size=4096", " ");
+        shadowenv.prepend_with_separator("NODE_OPTIONS", "--enable-source-maps", " ");
+        shadowenv.append_with_separator("NODE_OPTIONS", "--no-deprecation", " ");
+        shadowenv.prepend_with_separator("NODE_OPTIONS", "--max-old-space-size=4096", " ");
+        assert_eq!(
+            shadowenv.get("NODE_OPTIONS"),
+            Some("--enable-source-maps --no-deprecation --max-old-space-size=4096".to_string())
+        );
+
+        shadowenv.append_with_separator("FEATURES", "a", ";;");
+        shadowenv.append_with_separator("FEATURES", "b", ";;");
+        shadowenv.prepend_with_separator("FEATURES", "a", ";;");
+        assert_eq!(shadowenv.get("FEATURES"), Some("a;;b".to_string()));
+
+        // already there: nothing changes, and so there's nothing to undo.
+        shadowenv.append_with_separator("RUBYOPT", "-W0", " ");
+        assert_eq!(shadowenv.get("RUBYOPT"), Some("-W0".to_string()));
+
+        let data = shadowenv.shadowenv_data();
+        assert!(data.lists.is_empty());
+        assert_eq!(
+            data.scalars
+                .iter()
+                .map(|s| s.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["FEATURES", "NODE_OPTIONS"]
+        );
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(
+            after.get("NODE_OPTIONS"),
+            Some("--no-deprecation".to_string())
+        );
+        assert_eq!(after.get("FEATURES"), None);
+    }
+
+    #[test]
+    fn test_set_then_prepend_restores_path() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.set("PATH", Some("/opt/a:/opt/b"));
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/opt/c:/opt/a:/opt/b".to_string())
+        );
+
+        // none of the original entries survive, so putting them all back in order restores it.
+        let data = shadowenv.shadowenv_data();
+        assert!(data.scalars.is_empty());
+        assert_eq!(
+            data.lists,
+            vec![List {
+                name: "PATH".to_string(),
+                additions: vec![
+                    "/opt/c".to_string(),
+                    "/opt/a".to_string(),
+                    "/opt/b".to_string()
+                ],
+                deletions: vec!["/usr/bin".to_string(), "/bin".to_string()],
+            }]
+        );
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PATH"), Some("/usr/bin:/bin".to_string()));
+    }
+
+    #[test]
+    fn test_removals_restore_in_original_order() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/opt/a:/opt/b:/usr/bin")], Data::new());
+        shadowenv.remove_from_pathlist("PATH", "/opt/b");
+        shadowenv.remove_from_pathlist("PATH", "/opt/a");
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+
+        let data = shadowenv.shadowenv_data();
+        assert!(data.scalars.is_empty());
+        assert_eq!(
+            data.lists,
+            vec![List {
+                name: "PATH".to_string(),
+                additions: vec!["/opt/c".to_string()],
+                deletions: vec!["/opt/a".to_string(), "/opt/b".to_string()],
+            }]
+        );
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(
+            after.get("PATH"),
+            Some("/opt/a:/opt/b:/usr/bin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_prepend_then_set_restores_path() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+        shadowenv.set("PATH", Some("/bin:/opt/a"));
+        shadowenv.append_to_pathlist("PATH", "/usr/bin");
+
+        // the original entries are kept, but reordered: it's still undone as a list.
+        let data = shadowenv.shadowenv_data();
+        assert!(data.scalars.is_empty());
+        assert_eq!(data.lists.len(), 1);
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PATH"), Some("/usr/bin:/bin".to_string()));
+    }
+
+    #[test]
+    fn test_set_pathlist_keeps_entries_added_later() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.set("PATH", Some("/bin:/opt/a"));
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+
+        // something in the shell adds to PATH while the activation is live.
+        let mut exports = shadowenv.exports().unwrap();
+        let data = exports.remove("__shadowenv_data").unwrap().value.unwrap();
+        let data: Data = data.split_once(':').unwrap().1.parse().unwrap();
+        let mut env: HashMap<String, String> = exports
+            .into_iter()
+            .filter_map(|(k, export)| export.value.map(|v| (k, v)))
+            .collect();
+        env.insert(
+            "PATH".to_string(),
+            "/home/me/bin:/opt/c:/bin:/opt/a".to_string(),
+        );
+
+        // the original entries go back in front, and the one added later is kept.
+        let after = Shadowenv::new(env, data, 0);
+        assert_eq!(
+            after.get("PATH"),
+            Some("/usr/bin:/bin:/home/me/bin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_unset_then_prepend_restores_path() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.set("PATH", None);
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+        assert!(shadowenv.shadowenv_data().scalars.is_empty());
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PATH"), Some("/usr/bin:/bin".to_string()));
+    }
+
+    #[test]
+    fn test_unexported_vars_are_undone() {
+        let mut shadowenv = build_shadow_env(vec![], Data::new());
+        shadowenv.set_unexported("PROMPT_HELPER", Some("x"));
+        shadowenv.set("EXPORTED", Some("y"));
+
+        let exports = shadowenv.exports().unwrap();
+        assert!(!exports["PROMPT_HELPER"].exported);
+        assert!(exports["EXPORTED"].exported);
+
+        // the next hook can't see PROMPT_HELPER, yet has to unset it.
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PROMPT_HELPER"), None);
+        let exports = after.exports().unwrap();
+        assert_eq!(
+            exports["PROMPT_HELPER"],
+            Export {
+                value: None,
+                exported: true,
+            }
+        );
+        assert_eq!(exports["EXPORTED"].value, None);
+    }
+
+    #[test]
+    fn test_dedup_pathlists() {
+        let mut shadowenv = build_shadow_env(
+            vec![
+                ("PATH", "/usr/local/bin:/usr/bin:/bin:/usr/bin"),
+                ("NOT_A_LIST", "a:a"),
+            ],
+            Data::new(),
+        );
+        shadowenv.prepend_to_pathlist("PATH", "/opt/bin");
+        shadowenv.prepend_to_pathlist("PATH", "/usr/local/bin");
+        shadowenv.dedup_pathlists();
+
+        assert_eq!(
+            shadowenv.get("PATH"),
+            Some("/usr/local/bin:/opt/bin:/usr/bin:/bin".to_string())
+        );
+        assert_eq!(shadowenv.get("NOT_A_LIST"), Some("a:a".to_string()));
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(
+            after.get("PATH"),
+            Some("/usr/local/bin:/usr/bin:/bin:/usr/bin".to_string())
+        );
+    }
+
+    #[test]
+    fn test_pathlist_ops_alone_stay_list_backed() {
+        let mut shadowenv = build_shadow_env(vec![("PATH", "/usr/bin:/bin")], Data::new());
+        shadowenv.prepend_to_pathlist("PATH", "/opt/c");
+
+        let data = shadowenv.shadowenv_data();
+        assert!(data.scalars.is_empty());
+        assert_eq!(
+            data.lists,
+            vec![List {
+                name: "PATH".to_string(),
+                additions: vec!["/opt/c".to_string()],
+                deletions: vec![],
+            }]
+        );
+
+        let after = deactivated(&shadowenv);
+        assert_eq!(after.get("PATH"), Some("/usr/bin:/bin".to_string()));
     }
 }
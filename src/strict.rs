@@ -0,0 +1,218 @@
+//! Strict mode: checking, before anything runs, that every function a program calls exists.
+//!
+//! Ketos only notices a call to an undefined function when it gets there, and reports it in terms
+//! of its own internals. With SHADOWENV_STRICT set, a typo like `env/prepend-pathlist` is instead
+//! rejected up front, with a suggestion of what was probably meant.
+
+use crate::hash::{Source, SourceFile};
+use crate::lang::Location;
+use crate::loader::{call_sites, undocument};
+
+use failure::Fail;
+use ketos::lexer::Lexer;
+use ketos::name::{is_standard_name, Name};
+use ketos::parser::Parser;
+use ketos::scope::MasterScope;
+use ketos::{Interpreter, Scope, Value};
+use std::collections::HashSet;
+use std::fmt;
+
+/// Suggestions further than this many edits away from the unknown name aren't worth showing.
+const MAX_SUGGESTION_DISTANCE: usize = 3;
+
+#[derive(Fail, Debug)]
+pub struct UnknownFunction {
+    pub name: String,
+    /// the closest known function, if any is close enough to be a likely typo.
+    pub suggestion: Option<String>,
+    pub location: Option<Location>,
+}
+
+impl fmt::Display for UnknownFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown function `{}`", self.name)?;
+        if let Some(loc) = &self.location {
+            write!(f, " ({}:{})", loc.file, loc.line)?;
+        }
+        if let Some(suggestion) = &self.suggestion {
+            write!(f, ": did you mean `{}`?", suggestion)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether strict mode was asked for, given the value of SHADOWENV_STRICT.
+pub fn enabled(value: Option<&str>) -> bool {
+    !matches!(value, None | Some("") | Some("0") | Some("false"))
+}
+
+/// Check that every function called by the source and anything it includes is known to the
+/// interpreter (after its prelude has been loaded) or bound by the program itself. Files which
+/// don't parse are skipped here; evaluating them reports a better error.
+pub fn check(interp: &Interpreter, source: &Source) -> Result<(), UnknownFunction> {
+    let mut files: Vec<&SourceFile> = source.files.iter().collect();
+    files.sort();
+    files.extend(source.includes.values().flat_map(|s| s.files.iter()));
+
+    let mut calls: Vec<(Name, &SourceFile)> = vec![];
+    let mut bound: HashSet<Name> = HashSet::new();
+    for file in files {
+        let contents = undocument(&file.contents);
+        let lexer = Lexer::new(&contents, 0);
+        if let Ok(exprs) = Parser::new(interp.context(), lexer).parse_exprs() {
+            let mut file_calls = vec![];
+            for expr in &exprs {
+                walk(interp.scope(), expr, &mut file_calls, &mut bound);
+            }
+            calls.extend(file_calls.into_iter().map(|name| (name, file)));
+        }
+    }
+
+    let scope = interp.scope();
+    let unknown = calls.into_iter().find(|(name, _)| {
+        !(is_standard_name(*name) || scope.contains_name(*name) || bound.contains(name))
+    });
+    match unknown {
+        None => Ok(()),
+        Some((name, file)) => {
+            let name = scope.with_name(name, |s| s.to_string());
+            Err(UnknownFunction {
+                suggestion: closest(&name, &known_names(interp)),
+                location: find_call(interp, file, &name),
+                name,
+            })
+        }
+    }
+}
+
+/// Collect the names in call position, and the names the program binds itself (since those can
+/// be called too).
+fn walk(scope: &Scope, value: &Value, calls: &mut Vec<Name>, bound: &mut HashSet<Name>) {
+    let items = match value {
+        Value::List(items) => items,
+        _ => return,
+    };
+    let head = match &items[0] {
+        Value::Name(name) => Some((scope.with_name(*name, |s| s.to_string()), *name)),
+        _ => None,
+    };
+    let head = head.as_ref().map(|(text, name)| (text.as_str(), *name));
+
+    let mut rest = &items[..];
+    match head {
+        Some(("define", _)) | Some(("macro", _)) | Some(("lambda", _)) => {
+            if let Some(params) = items.get(1) {
+                bind(params, bound);
+            }
+            rest = &items[1..];
+            if let Some(Value::List(_)) = items.get(1) {
+                rest = &items[2..];
+            }
+        }
+        Some(("let", _)) | Some(("when-let", _)) => {
+            if let Some(Value::List(bindings)) = items.get(1) {
+                for binding in bindings.iter() {
+                    match binding {
+                        Value::List(pair) => {
+                            bind(&pair[0], bound);
+                            pair[1..].iter().for_each(|v| walk(scope, v, calls, bound));
+                        }
+                        other => bind(other, bound),
+                    }
+                }
+                rest = &items[2..];
+            }
+        }
+        // struct fields look like calls, but aren't.
+        Some(("struct", _)) => return,
+        Some((_, name)) => {
+            calls.push(name);
+            rest = &items[1..];
+        }
+        None => (),
+    }
+    rest.iter().for_each(|v| walk(scope, v, calls, bound));
+}
+
+fn bind(value: &Value, bound: &mut HashSet<Name>) {
+    match value {
+        Value::Name(name) => {
+            bound.insert(*name);
+        }
+        Value::List(items) => items.iter().for_each(|v| bind(v, bound)),
+        _ => (),
+    }
+}
+
+fn known_names(interp: &Interpreter) -> Vec<String> {
+    let scope = interp.scope();
+    let mut names: Vec<Name> = MasterScope::names().collect();
+    scope.with_values(|values| names.extend(values.iter().map(|(n, _)| *n)));
+    scope.with_macros(|macros| names.extend(macros.iter().map(|(n, _)| *n)));
+    scope.with_constants(|constants| names.extend(constants.iter().map(|(n, _)| *n)));
+    names
+        .into_iter()
+        .map(|name| scope.with_name(name, |s| s.to_string()))
+        .filter(|name| !name.starts_with("__shadowenv__"))
+        .collect()
+}
+
+fn closest(name: &str, candidates: &[String]) -> Option<String> {
+    candidates
+        .iter()
+        .map(|candidate| (levenshtein(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= MAX_SUGGESTION_DISTANCE)
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = prev[j] + if ca == *cb { 0 } else { 1 };
+            curr.push(substitution.min(prev[j + 1] + 1).min(curr[j] + 1));
+        }
+        prev = curr;
+    }
+    prev[b.len()]
+}
+
+fn find_call(interp: &Interpreter, file: &SourceFile, name: &str) -> Option<Location> {
+    let (offset, _) = *call_sites(interp, &file.contents, name).first()?;
+    Some(Location::at(file, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levenshtein() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("env/get", "env/get"), 0);
+        assert_eq!(levenshtein("env/gte", "env/get"), 2);
+        assert_eq!(levenshtein("env/st", "env/set"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn test_closest() {
+        let candidates = vec!["env/set".to_string(), "env/get".to_string()];
+        assert_eq!(
+            closest("env/sett", &candidates),
+            Some("env/set".to_string())
+        );
+        assert_eq!(closest("something-else", &candidates), None);
+    }
+
+    #[test]
+    fn test_enabled() {
+        assert!(!enabled(None));
+        assert!(!enabled(Some("")));
+        assert!(!enabled(Some("0")));
+        assert!(enabled(Some("1")));
+    }
+}
@@ -0,0 +1,41 @@
+//! Helpers for testing the output of each `VariableOutputMode` against golden files. These are
+//! available to other crates with the `testing` feature.
+
+use crate::hook::{self, VariableOutputMode};
+use crate::shadowenv::Shadowenv;
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Render a shadowenv as the given mode would print it.
+pub fn render(shadowenv: &Shadowenv, mode: VariableOutputMode) -> String {
+    let mut out: Vec<u8> = vec![];
+    hook::write_env(&mut out, shadowenv, &mode, false).expect("failed to render shadowenv");
+    String::from_utf8(out).expect("rendered shadowenv is not UTF-8")
+}
+
+/// Assert that `actual` matches the contents of the golden file at `path`. Run with
+/// SHADOWENV_UPDATE_GOLDEN=1 to (re)write the golden file instead.
+pub fn assert_golden(path: &Path, actual: &str) {
+    if env::var_os("SHADOWENV_UPDATE_GOLDEN").is_some() {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "couldn't read golden file {}: {} (run with SHADOWENV_UPDATE_GOLDEN=1 to create it)",
+            path.display(),
+            e
+        )
+    });
+    assert_eq!(
+        expected,
+        actual,
+        "output doesn't match golden file {}",
+        path.display()
+    );
+}
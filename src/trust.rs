@@ -1,3 +1,4 @@
+use crate::hash::{Hash, Source};
 use crate::loader;
 
 use ed25519_dalek::Keypair;
@@ -25,20 +26,40 @@ pub struct NotTrusted {
 }
 
 pub fn is_dir_trusted(dir: &PathBuf) -> Result<bool, Error> {
-    let signer = load_or_generate_signer().unwrap();
-
     let root = match loader::find_root(&dir.to_path_buf(), loader::DEFAULT_RELATIVE_COMPONENT)? {
         None => return Err(NoShadowenv {}.into()),
         Some(r) => r,
     };
+    is_root_trusted(&root)
+}
+
+/// Like `is_dir_trusted`, but for a `.shadowenv.d` that has already been found, saving another
+/// search up the tree.
+pub fn is_root_trusted(root: &Path) -> Result<bool, Error> {
+    is_source_trusted(root, &loader::load_unresolved(root)?)
+}
+
+/// Like `is_root_trusted`, for a `.shadowenv.d` already loaded with `loader::load_unresolved`, so
+/// that it needn't be read and hashed again.
+pub fn is_source_trusted(root: &Path, source: &Source) -> Result<bool, Error> {
+    let signer = load_or_generate_signer().unwrap();
+    is_trusted_by(&signer, root, source.hash()?)
+}
 
+/// What gets signed when trusting a `.shadowenv.d`: its path, and the hash of the programs in it
+/// and the data files they read. Changing, adding or removing any of them revokes the trust, so
+/// that code or data nobody trusted is never run under an earlier `shadowenv trust`.
+fn trusted_message(root: &Path, hash: u64) -> Vec<u8> {
+    format!("{}\n{}", root.display(), Hash { hash }).into_bytes()
+}
+
+fn is_trusted_by(signer: &Keypair, root: &Path, hash: u64) -> Result<bool, Error> {
     let pubkey = signer.public;
     let fingerprint = hex::encode(&pubkey.as_bytes()[0..4]);
 
-    let d = root.display().to_string();
-    let msg = d.as_bytes();
+    let msg = trusted_message(root, hash);
 
-    let path = trust_file(&root, fingerprint);
+    let path = trust_file(root, fingerprint);
     let r_o_bytes: Result<Option<Vec<u8>>, Error> = match fs::read(path) {
         Ok(bytes) => Ok(Some(bytes)),
         Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
@@ -49,7 +70,7 @@ pub fn is_dir_trusted(dir: &PathBuf) -> Result<bool, Error> {
         None => Ok(false),
         Some(bytes) => {
             let sig = Signature::new(from_vec(bytes));
-            Ok(signer.verify(msg, &sig).is_ok())
+            Ok(signer.verify(&msg, &sig).is_ok())
         }
     }
 }
@@ -77,7 +98,7 @@ fn load_or_generate_signer() -> Result<Keypair, Error> {
                 Ok(f) => f,
             };
 
-            file.write(&seed.to_bytes())?;
+            file.write_all(&seed.to_bytes())?;
 
             Ok(seed)
         }
@@ -93,14 +114,18 @@ pub fn run() -> Result<(), Error> {
         Some(r) => r,
     };
 
-    let d = root.display().to_string();
-    let msg = d.as_bytes();
-    let sig = signer.sign(msg);
+    sign(&signer, &root)?;
+    write_gitignore(root)
+}
+
+fn sign(signer: &Keypair, root: &Path) -> Result<(), Error> {
+    let msg = trusted_message(root, loader::content_hash(root)?);
+    let sig = signer.sign(&msg);
 
     let pubkey = signer.public;
     let fingerprint = hex::encode(&pubkey.as_bytes()[0..4]);
 
-    let path = trust_file(&root, fingerprint);
+    let path = trust_file(root, fingerprint);
 
     let mut file = match File::create(OsString::from(&path)) {
         // TODO: error type
@@ -108,8 +133,6 @@ pub fn run() -> Result<(), Error> {
         Ok(file) => file,
     };
 
-    write_gitignore(root)?;
-
     match file.write_all(&sig.to_bytes()) {
         // TODO: error type
         Err(why) => panic!("couldn't write to {:?}: {}", path, why),
@@ -148,6 +171,80 @@ fn write_gitignore(root: PathBuf) -> Result<(), Error> {
     Ok(())
 }
 
-fn trust_file(root: &PathBuf, fingerprint: String) -> PathBuf {
+fn trust_file(root: &Path, fingerprint: String) -> PathBuf {
     root.join(format!(".trust-{}", fingerprint))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn trusted(signer: &Keypair, root: &Path) -> bool {
+        is_trusted_by(signer, root, loader::content_hash(root).unwrap()).unwrap()
+    }
+
+    fn trusted_root() -> (tempfile::TempDir, PathBuf, Keypair) {
+        let temp_dir = tempdir().unwrap();
+        let root = temp_dir.path().join(".shadowenv.d");
+        fs::create_dir(&root).unwrap();
+        fs::write(root.join("500_app.lisp"), "(env/set \"A\" \"1\")").unwrap();
+        let signer = Keypair::generate(&mut OsRng {});
+        assert!(!trusted(&signer, &root));
+        sign(&signer, &root).unwrap();
+        assert!(trusted(&signer, &root));
+        (temp_dir, root, signer)
+    }
+
+    #[test]
+    fn test_editing_a_trusted_file_revokes_trust() {
+        let (_temp_dir, root, signer) = trusted_root();
+        fs::write(root.join("500_app.lisp"), "(env/set \"A\" \"2\")").unwrap();
+        assert!(!trusted(&signer, &root));
+
+        // putting it back the way it was restores the trust.
+        fs::write(root.join("500_app.lisp"), "(env/set \"A\" \"1\")").unwrap();
+        assert!(trusted(&signer, &root));
+    }
+
+    #[test]
+    fn test_adding_a_program_revokes_trust() {
+        let (_temp_dir, root, signer) = trusted_root();
+        fs::write(root.join("600_more.lisp"), "(env/set \"B\" \"1\")").unwrap();
+        assert!(!trusted(&signer, &root));
+    }
+
+    #[test]
+    fn test_editing_a_data_file_revokes_trust() {
+        let (temp_dir, root, signer) = trusted_root();
+        let versions = temp_dir.path().join("versions.json");
+        fs::write(&versions, r#"{"bin": "/opt/tool/bin"}"#).unwrap();
+        fs::write(
+            root.join("500_app.lisp"),
+            r#"(env/prepend-to-pathlist "PATH" (read-json "versions.json" "bin"))"#,
+        )
+        .unwrap();
+        sign(&signer, &root).unwrap();
+        assert!(trusted(&signer, &root));
+
+        // a pulled change to what the program reads is as much a change as one to the program.
+        fs::write(&versions, r#"{"bin": "/tmp/evil"}"#).unwrap();
+        assert!(!trusted(&signer, &root));
+    }
+
+    #[test]
+    fn test_other_files_dont_revoke_trust() {
+        let (_temp_dir, root, signer) = trusted_root();
+        fs::write(root.join("README.md"), "# not lisp").unwrap();
+        assert!(trusted(&signer, &root));
+    }
+
+    #[test]
+    fn test_path_only_signatures_are_not_trusted() {
+        let (_temp_dir, root, signer) = trusted_root();
+        let sig = signer.sign(root.display().to_string().as_bytes());
+        let fingerprint = hex::encode(&signer.public.as_bytes()[0..4]);
+        fs::write(trust_file(&root, fingerprint), sig.to_bytes()).unwrap();
+        assert!(!trusted(&signer, &root));
+    }
+}
@@ -1,8 +1,24 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::Path;
 use std::result::Result;
+use std::str::FromStr;
 
 use failure::Error;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use serde_derive::{Deserialize, Serialize};
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+
+/// The version of the $__shadowenv_data format, as reported by `capabilities`. Fields can be added
+/// without changing it, since older versions ignore them; anything else has to bump it.
+pub const DATA_FORMAT_VERSION: u32 = 1;
+
+/// Marks undo data which has been compressed (deflated, then base64-encoded) rather than being
+/// plain JSON, which always starts with `{`.
+pub const COMPRESSED_PREFIX: &str = "z:";
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Scalar {
     #[serde(default)]
     pub name: String,
@@ -10,36 +26,84 @@ pub struct Scalar {
     pub original: Option<String>,
     #[serde(default)]
     pub current: Option<String>,
+    /// set with `env/set-unexported`. The hook can't see these in its env, so undoing them can't
+    /// depend on their current value.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub unexported: bool,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
 pub struct List {
     #[serde(default)]
     pub name: String,
     #[serde(default)]
     pub additions: Vec<String>,
+    /// entries removed from the original value, in the order they appeared there.
     #[serde(default)]
     pub deletions: Vec<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Eq, PartialEq)]
 pub struct Data {
+    /// the `Summary` of the activation, kept first so that shells can get at it without parsing
+    /// any JSON. Only present in $__shadowenv_data, and never when nothing is active.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
     #[serde(default)]
     pub scalars: Vec<Scalar>,
     #[serde(default)]
     pub lists: Vec<List>,
+    /// directories handed out by `cache-dir` and `mktempdir`, removed once we leave this activation.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cache_dirs: Vec<String>,
+    /// the hash of the activation `shadowenv unload` undid, if nothing has been activated since.
+    /// The hook leaves that version of the project inactive until the shell leaves it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unloaded: Option<String>,
 }
 
-impl Data {
-    pub fn from_str(data: &str) -> Result<Data, Error> {
-        let d: Data = serde_json::from_str(data)?;
+/// Parse undo data as written by `encode`, compressed or not.
+impl FromStr for Data {
+    type Err = Error;
+
+    fn from_str(data: &str) -> Result<Data, Error> {
+        let d: Data = match data.strip_prefix(COMPRESSED_PREFIX) {
+            Some(encoded) => {
+                let compressed = base64::decode(encoded)?;
+                let mut json = String::new();
+                DeflateDecoder::new(&compressed[..]).read_to_string(&mut json)?;
+                serde_json::from_str(&json)?
+            }
+            None => serde_json::from_str(data)?,
+        };
         Ok(d)
     }
+}
+
+impl Data {
+    /// Serialize undo data for $__shadowenv_data: as JSON, or with `compress`, as deflated JSON
+    /// which takes up much less of the environment when there are many variables to undo.
+    pub fn encode(&self, compress: bool) -> Result<String, Error> {
+        let json = serde_json::to_string(self)?;
+        if !compress {
+            return Ok(json);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(json.as_bytes())?;
+        Ok(format!(
+            "{}{}",
+            COMPRESSED_PREFIX,
+            base64::encode(encoder.finish()?)
+        ))
+    }
 
     pub fn new() -> Self {
         Data {
+            summary: None,
             scalars: vec![],
             lists: vec![],
+            cache_dirs: vec![],
+            unloaded: None,
         }
     }
 
@@ -48,6 +112,7 @@ impl Data {
             name,
             original,
             current,
+            unexported: false,
         })
     }
 
@@ -59,3 +124,121 @@ impl Data {
         })
     }
 }
+
+/// What an activation manages, in a form cheap enough for a shell function to check on every
+/// prompt: the project directory whose `.shadowenv.d` is active and the names of the variables it
+/// set, as `<root>;<name>,<name>...`. The hash of that `.shadowenv.d` is already the prefix of
+/// $__shadowenv_data.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    pub root: String,
+    pub vars: Vec<String>,
+}
+
+impl Summary {
+    pub fn of(root: &str, data: &Data) -> Summary {
+        let mut vars: Vec<String> = data
+            .scalars
+            .iter()
+            .map(|s| s.name.clone())
+            .chain(data.lists.iter().map(|l| l.name.clone()))
+            .collect();
+        vars.sort();
+        vars.dedup();
+        Summary {
+            root: root.to_string(),
+            vars,
+        }
+    }
+
+    /// Find the summary in a whole $__shadowenv_data value.
+    pub fn from_shadowenv_data(shadowenv_data: &str) -> Option<Summary> {
+        let (_, json) = shadowenv_data.split_once(':')?;
+        Data::from_str(json).ok()?.summary?.parse().ok()
+    }
+
+    /// Whether the hook has to run for the shell to be in `dir`. Anywhere outside of the project it
+    /// does. Within it, the hook would find the same `.shadowenv.d` again, and change nothing: that
+    /// holds for as long as the `.shadowenv.d` isn't edited, and unless `dir` is in a project of its
+    /// own nested inside this one, which the shell still has to look out for.
+    pub fn needs_hook(&self, dir: &Path) -> bool {
+        !dir.starts_with(&self.root)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{};{}", self.root, self.vars.join(","))
+    }
+}
+
+impl FromStr for Summary {
+    type Err = Error;
+
+    fn from_str(summary: &str) -> Result<Self, Error> {
+        // variable names have no `;`, but the root might.
+        let (root, vars) = summary
+            .rsplit_once(';')
+            .ok_or_else(|| failure::err_msg("summary has no `;`"))?;
+        if root.is_empty() {
+            return Err(failure::err_msg("summary has no project root"));
+        }
+        Ok(Summary {
+            root: root.to_string(),
+            vars: vars
+                .split(',')
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn many_scalars() -> Data {
+        let mut data = Data::new();
+        for i in 0..200 {
+            data.add_scalar(
+                format!("SCRUBBED_VAR_{}", i),
+                Some(format!("original value {}", i)),
+                None,
+            );
+        }
+        data.add_list(
+            "PATH".to_string(),
+            vec!["/opt/bin".to_string()],
+            vec!["/usr/local/bin".to_string()],
+        );
+        data
+    }
+
+    #[test]
+    fn test_plain_roundtrip() {
+        for data in &[Data::new(), many_scalars()] {
+            let encoded = data.encode(false).unwrap();
+            assert!(encoded.starts_with('{'));
+            assert_eq!(&Data::from_str(&encoded).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_compressed_roundtrip() {
+        for data in &[Data::new(), many_scalars()] {
+            let encoded = data.encode(true).unwrap();
+            assert!(encoded.starts_with(COMPRESSED_PREFIX));
+            assert_eq!(&Data::from_str(&encoded).unwrap(), data);
+        }
+
+        let data = many_scalars();
+        assert!(data.encode(true).unwrap().len() * 4 < data.encode(false).unwrap().len());
+    }
+
+    #[test]
+    fn test_from_str_rejects_corrupt_compressed_data() {
+        assert!(Data::from_str("z:not base64!").is_err());
+        assert!(Data::from_str(&format!("z:{}", base64::encode("not deflate"))).is_err());
+    }
+}
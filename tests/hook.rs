@@ -0,0 +1,304 @@
+//! The `shadowenv hook` binary, as shells run it.
+
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+fn hook(dir: &TempDir, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+        .arg("hook")
+        .args(args)
+        .args(&["--shellpid", "1"])
+        .current_dir(dir.path())
+        .env_remove("__shadowenv_data")
+        .env("HOME", dir.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn exit_code_on_no_change() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = hook(&dir, &["--exit-code-on-no-change"]);
+    assert_eq!(output.status.code(), Some(75));
+    assert!(output.stdout.is_empty());
+
+    let output = hook(&dir, &[]);
+    assert_eq!(output.status.code(), Some(0));
+
+    // a porcelain consumer still gets told that shadowenv is done.
+    let output = hook(
+        &dir,
+        &["--porcelain", "--end-marker", "--exit-code-on-no-change"],
+    );
+    assert_eq!(output.status.code(), Some(75));
+    assert_eq!(stdout(output), "\x00\x1F\x1F\x1E");
+}
+
+/// A temporary project whose `.shadowenv.d` holds `program`, trusted.
+fn trusted_project(program: &str) -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join(".shadowenv.d")).unwrap();
+    std::fs::write(dir.path().join(".shadowenv.d/env.lisp"), program).unwrap();
+    let trust = Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+        .arg("trust")
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .status()
+        .unwrap();
+    assert!(trust.success());
+    dir
+}
+
+fn stdout(output: Output) -> String {
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn exit_code_on_change() {
+    let dir = trusted_project(r#"(env/set "GREETING" "hi")"#);
+
+    let output = hook(&dir, &["--exit-code-on-no-change", "--posix"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(output);
+    assert!(stdout.contains("GREETING"));
+    // the summary names the project, for shells to compare $PWD against.
+    let root = std::fs::canonicalize(dir.path()).unwrap();
+    assert!(stdout.contains(&format!(r#""summary":"{};GREETING""#, root.display())));
+}
+
+#[test]
+fn version_directive() {
+    let dir = trusted_project(
+        ";; @shadowenv-version 1.0\n;; the greeting\n(env/set \"GREETING\" \"hi\")\n",
+    );
+    let output = hook(&dir, &["--posix"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(output).contains("GREETING"));
+
+    let dir = trusted_project(";; @shadowenv-version 999\n(env/set \"GREETING\" \"hi\")\n");
+    let output = hook(&dir, &["--posix", "--silent"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stdout(output).contains("GREETING"));
+}
+
+#[test]
+fn unknown_shell() {
+    let dir = trusted_project(r#"(env/set "GREETING" "hi")"#);
+
+    let output = hook(&dir, &["--shell", "zsh"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(output).contains("export GREETING"));
+
+    let output = hook(&dir, &["--shell", "tcsh"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("'tcsh' isn't a shell"));
+}
+
+#[test]
+fn exec_in_an_activated_shell() {
+    let dir = trusted_project(
+        r#"(env/prepend-to-pathlist "PATH" "/project/bin") (env/set-exec-only "DEBUG" "1")"#,
+    );
+    let shadowenv = |args: &[&str], path: &str, data: Option<&str>| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_shadowenv"));
+        command
+            .args(args)
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .env("PATH", path)
+            .env_remove("__shadowenv_data")
+            .env_remove("DEBUG");
+        if let Some(data) = data {
+            command.env("__shadowenv_data", data);
+        }
+        command.output().unwrap()
+    };
+
+    let output = shadowenv(
+        &["hook", "--json", "--shellpid", "1"],
+        "/usr/bin:/bin",
+        None,
+    );
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let path = json["exported"]["PATH"].as_str().unwrap();
+    let data = json["exported"]["__shadowenv_data"].as_str().unwrap();
+    assert_eq!(path, "/project/bin:/usr/bin:/bin");
+
+    // from the shell the hook activated, exec still sets the exec-only variables.
+    let output = shadowenv(
+        &["exec", "--", "sh", "-c", r#"echo "$DEBUG $PATH""#],
+        path,
+        Some(data),
+    );
+    assert_eq!(stdout(output), "1 /project/bin:/usr/bin:/bin\n");
+}
+
+/// Run the hook in `dir` with SHADOWENV_EVENT_FD naming the write end of a pipe, returning its
+/// output and the events read from the other end.
+fn hook_with_events(
+    dir: &std::path::Path,
+    home: &TempDir,
+    env: &[(&str, &str)],
+) -> (Output, String) {
+    with_events(&["hook", "--posix", "--shellpid", "1"], dir, home, env)
+}
+
+/// Run shadowenv with `args` in a shell whose env has `env` added, with SHADOWENV_EVENT_FD set to
+/// a pipe, returning what came through it.
+fn with_events(
+    args: &[&str],
+    dir: &std::path::Path,
+    home: &TempDir,
+    env: &[(&str, &str)],
+) -> (Output, String) {
+    use std::io::Read;
+    use std::os::unix::io::FromRawFd;
+
+    let mut fds = [0; 2];
+    assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+    let [read_fd, write_fd] = fds;
+    // the child inherits the write end, as it isn't close-on-exec.
+    let output = Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+        .args(args)
+        .current_dir(dir)
+        .env("HOME", home.path())
+        .envs(env.iter().copied())
+        .env("SHADOWENV_EVENT_FD", write_fd.to_string())
+        .output()
+        .unwrap();
+    unsafe { libc::close(write_fd) };
+
+    let mut events = String::new();
+    unsafe { std::fs::File::from_raw_fd(read_fd) }
+        .read_to_string(&mut events)
+        .unwrap();
+    (output, events)
+}
+
+#[test]
+fn events_on_a_pipe() {
+    let dir = trusted_project(r#"(provide "ruby" "3.1") (env/set "GREETING" "hi")"#);
+
+    let (output, events) = hook_with_events(dir.path(), &dir, &[("__shadowenv_data", "")]);
+    assert_eq!(output.status.code(), Some(0));
+    let data = stdout(output)
+        .lines()
+        .find_map(|line| line.strip_prefix("export __shadowenv_data='"))
+        .map(|data| data.trim_end_matches('\'').to_string())
+        .unwrap();
+    let (hash, _) = data.split_once(':').unwrap();
+    assert_eq!(
+        events,
+        format!(
+            "{{\"event\":\"activated\",\"hash\":\"{}\",\"features\":[\"ruby:3.1\"]}}\n",
+            hash
+        )
+    );
+
+    // leaving for somewhere without a .shadowenv.d.
+    let elsewhere = tempfile::tempdir().unwrap();
+    let active = [("__shadowenv_data", data.as_str()), ("GREETING", "hi")];
+    let (output, events) = hook_with_events(elsewhere.path(), &dir, &active);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(events, "{\"event\":\"deactivated\"}\n");
+
+    // `unload` deactivates too, but leaving afterwards, or unloading again, changes nothing.
+    let (output, events) = with_events(&["unload", "--posix"], dir.path(), &dir, &active);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(events, "{\"event\":\"deactivated\"}\n");
+    let unloaded = stdout(output)
+        .lines()
+        .find_map(|line| line.strip_prefix("export __shadowenv_data='"))
+        .map(|data| data.trim_end_matches('\'').to_string())
+        .unwrap();
+    let unloaded = [("__shadowenv_data", unloaded.as_str())];
+    let (output, events) = with_events(&["unload", "--posix"], dir.path(), &dir, &unloaded);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(events, "");
+    let (output, events) = hook_with_events(elsewhere.path(), &dir, &unloaded);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(events, "");
+}
+
+#[test]
+fn events_are_not_written_to_unusable_descriptors() {
+    let dir = trusted_project(r#"(env/set "GREETING" "hi")"#);
+    for fd in &["-1", "1", "2", "999999"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+            .args(&["hook", "--posix", "--shellpid", "1"])
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .env("__shadowenv_data", "")
+            .env("SHADOWENV_EVENT_FD", fd)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(0), "SHADOWENV_EVENT_FD={}", fd);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let stdout = stdout(output);
+        assert!(stdout.contains("export GREETING=hi\n"));
+        assert!(!stdout.contains("\"event\"") && !stderr.contains("\"event\""));
+    }
+}
+
+#[test]
+fn no_export_data_activates_and_leaves() {
+    let dir = trusted_project(r#"(env/prepend-to-pathlist "PATH" "/opt/x")"#);
+    let elsewhere = tempfile::tempdir().unwrap();
+    // as a shell hook does it: the data is only ever given as an argument, never in the env.
+    let hook = |cwd: &std::path::Path, args: &[&str], path: &str, data: &str| {
+        Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+            .arg("hook")
+            .args(args)
+            .args(&["--shellpid", "1", data])
+            .current_dir(cwd)
+            .env("HOME", dir.path())
+            .env("PATH", path)
+            .env_remove("__shadowenv_data")
+            .output()
+            .unwrap()
+    };
+
+    let output = hook(dir.path(), &["--no-export-data"], "/usr/bin:/bin", "");
+    assert_eq!(output.status.code(), Some(0));
+    let activated = stdout(output);
+    assert!(activated.contains("export PATH='/opt/x:/usr/bin:/bin'\n"));
+    let data = activated
+        .lines()
+        .find_map(|line| line.strip_prefix("__shadowenv_data='"))
+        .and_then(|data| data.strip_suffix('\''))
+        .unwrap();
+
+    let output = hook(
+        elsewhere.path(),
+        &["--no-export-data"],
+        "/opt/x:/usr/bin:/bin",
+        data,
+    );
+    assert_eq!(output.status.code(), Some(0));
+    assert!(stdout(output).contains("export PATH='/usr/bin:/bin'\n"));
+
+    // without the data to go on, the hook couldn't leave, so that's refused outright.
+    let output = Command::new(env!("CARGO_BIN_EXE_shadowenv"))
+        .args(&["hook", "--no-export-data", "--shellpid", "1"])
+        .current_dir(dir.path())
+        .env("HOME", dir.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    // nor does it mean anything for output that isn't eval'd by a posix shell or fish.
+    for format in &["--porcelain", "--json", "--powershell"] {
+        let output = hook(
+            dir.path(),
+            &["--no-export-data", format],
+            "/usr/bin:/bin",
+            "",
+        );
+        assert_eq!(output.status.code(), Some(1), "{}", format);
+    }
+}